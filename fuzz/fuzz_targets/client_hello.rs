@@ -0,0 +1,49 @@
+#![no_main]
+
+#[macro_use]
+extern crate libfuzzer_sys;
+#[macro_use]
+extern crate log;
+
+// tnexus is a binary crate, so the parser is compiled in as a module of its own
+// (libfuzzer needs a recent nightly, which frowns upon try!)
+#[allow(dead_code, deprecated)]
+#[path = "../../src/tls.rs"]
+mod tls;
+
+use tls::{ParseResult, MAX_HELLO_SIZE};
+
+fuzz_target!(|data: &[u8]| {
+    check(data, MAX_HELLO_SIZE);
+    // small enough to hit the limit with small inputs
+    check(data, 512);
+});
+
+/// Besides not panicking on any input, the parser must keep its promises to the multiplexer.
+fn check(data: &[u8], max_size: usize) {
+    let result = tls::parse_client_hello(data, max_size);
+
+    match result {
+        ParseResult::NeedMore(n) => {
+            assert!(n > data.len(), "NeedMore({}) with {} bytes at hand", n, data.len());
+            return;
+        },
+        ParseResult::Hello(ref hello) => {
+            assert_eq!(hello.sni(), hello.server_names.first().map(|name| &name[..]));
+            assert!(hello.session_id.len() <= 32);
+        },
+        _ => (),
+    }
+
+    // the multiplexer parses the buffer again whenever more bytes arrive, so a decision
+    // must neither depend on what follows it...
+    let mut more = data.to_vec();
+    more.extend_from_slice(&[22, 3, 1, 0, 1, 1]);
+    assert_eq!(format!("{:?}", tls::parse_client_hello(&more, max_size)), format!("{:?}", result));
+
+    // ...nor be taken differently on fewer bytes
+    match tls::parse_client_hello(&data[.. data.len() / 2], max_size) {
+        ParseResult::NeedMore(_) => (),
+        early => assert_eq!(format!("{:?}", early), format!("{:?}", result)),
+    }
+}
@@ -0,0 +1,258 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// A range of IP addresses, like "10.0.0.0/8" or "2001:db8::/32" (a bare address is a range of one).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cidr {
+    ip: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn parse(s: &str) -> Result<Cidr, String> {
+        let bad = || format!("Bad address range {}, expected \"ip/prefix-length\" or \"ip\"", s);
+
+        let (ip, prefix_len) = match s.find('/') {
+            Some(slash) => (&s[..slash], Some(&s[slash + 1..])),
+            None => (s, None),
+        };
+        let ip: IpAddr = try!(ip.parse().map_err(|_| bad()));
+        let max_len = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => match len.parse::<u8>() {
+                Ok(len) if len <= max_len => len,
+                _ => return Err(bad()),
+            },
+            None => max_len,
+        };
+
+        // like the addresses it is matched with
+        match unmapped(ip) {
+            IpAddr::V4(v4) if ip.is_ipv6() && prefix_len >= 96 => Ok(Cidr {
+                ip: IpAddr::V4(v4),
+                prefix_len: prefix_len - 96,
+            }),
+            _ => Ok(Cidr {
+                ip: ip,
+                prefix_len: prefix_len,
+            }),
+        }
+    }
+
+    /// Whether the address is in the range (IPv4-mapped IPv6 addresses count as IPv4 addresses).
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.ip, unmapped(ip)) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => same_prefix(&range.octets(), &ip.octets(), self.prefix_len),
+            (IpAddr::V6(range), IpAddr::V6(ip)) => same_prefix(&range.octets(), &ip.octets(), self.prefix_len),
+            _ => false,
+        }
+    }
+}
+
+/// Like in the config.
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.ip, self.prefix_len)
+    }
+}
+
+/// Which clients may connect, by IP address: none of the deny ranges may match, and
+/// one of the allow ranges must (if there are any).
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Acl {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl Acl {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Whether the client may connect. Clients without an IP address (i.e. of a Unix domain
+    /// socket) may only connect if there is no allow list.
+    pub fn permits(&self, client: Option<IpAddr>) -> bool {
+        match client {
+            Some(ip) => !self.deny.iter().any(|cidr| cidr.contains(ip))
+                && (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))),
+            None => self.allow.is_empty(),
+        }
+    }
+}
+
+/// Whether a peer may send PROXY protocol headers, i.e. is one of the trusted proxies. Peers of a
+/// Unix domain socket have no address, they are trusted as far as the file permissions let them in.
+pub fn trusts(proxies: &[Cidr], peer: Option<IpAddr>) -> bool {
+    match peer {
+        Some(ip) => proxies.iter().any(|cidr| cidr.contains(ip)),
+        None => true,
+    }
+}
+
+/// The IPv4 address of an IPv4-mapped IPv6 address (e.g. of a client of a dual-stack socket).
+fn unmapped(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            if s[0] == 0 && s[1] == 0 && s[2] == 0 && s[3] == 0 && s[4] == 0 && s[5] == 0xffff {
+                let o = v6.octets();
+                IpAddr::V4(Ipv4Addr::new(o[12], o[13], o[14], o[15]))
+            } else {
+                ip
+            }
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Whether the first `len` bits of both addresses are the same.
+fn same_prefix(a: &[u8], b: &[u8], len: u8) -> bool {
+    let len = len as usize;
+    let (bytes, bits) = (len / 8, len % 8);
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    if bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - bits);
+    a[bytes] & mask == b[bytes] & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidr(s: &str) -> Cidr {
+        Cidr::parse(s).unwrap()
+    }
+
+    fn acl(allow: &[&str], deny: &[&str]) -> Acl {
+        Acl {
+            allow: allow.iter().map(|s| cidr(s)).collect(),
+            deny: deny.iter().map(|s| cidr(s)).collect(),
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(cidr("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("192.0.2.1").to_string(), "192.0.2.1/32");
+        assert_eq!(cidr("2001:db8::/32").to_string(), "2001:db8::/32");
+        assert_eq!(cidr("2001:db8::1").to_string(), "2001:db8::1/128");
+        assert_eq!(cidr("::ffff:192.0.2.0/120").to_string(), "192.0.2.0/24");
+        assert_eq!(cidr("::ffff:0.0.0.0/80").to_string(), "::ffff:0.0.0.0/80");
+
+        for bad in ["10.0.0.0/33", "2001:db8::/129", "10.0.0.0/", "10.0.0.0/-1", "10.0.0/8", "example.com", ""].iter() {
+            assert_eq!(Cidr::parse(bad),
+                       Err(format!("Bad address range {}, expected \"ip/prefix-length\" or \"ip\"", bad)));
+        }
+    }
+
+    #[test]
+    fn unaligned_prefixes() {
+        let range = cidr("192.0.2.64/26");
+        assert!(!range.contains(ip("192.0.2.63")));
+        assert!(range.contains(ip("192.0.2.64")));
+        assert!(range.contains(ip("192.0.2.127")));
+        assert!(!range.contains(ip("192.0.2.128")));
+
+        let range = cidr("10.128.0.0/9");
+        assert!(range.contains(ip("10.255.255.255")));
+        assert!(!range.contains(ip("10.127.255.255")));
+
+        let range = cidr("2001:db8:8000::/33");
+        assert!(range.contains(ip("2001:db8:ffff::1")));
+        assert!(!range.contains(ip("2001:db8:7fff::1")));
+
+        // the host bits of the range do not matter
+        assert!(cidr("192.0.2.77/26").contains(ip("192.0.2.64")));
+        assert!(cidr("192.0.2.1/31").contains(ip("192.0.2.0")));
+        assert!(!cidr("192.0.2.1/31").contains(ip("192.0.2.2")));
+    }
+
+    #[test]
+    fn everything() {
+        let range = cidr("0.0.0.0/0");
+        assert!(range.contains(ip("0.0.0.0")));
+        assert!(range.contains(ip("255.255.255.255")));
+        assert!(range.contains(ip("::ffff:192.0.2.1")));
+        assert!(!range.contains(ip("2001:db8::1")));
+
+        let range = cidr("::/0");
+        assert!(range.contains(ip("2001:db8::1")));
+        assert!(range.contains(ip("::1")));
+        assert!(!range.contains(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn mapped_addresses() {
+        // a client of a dual-stack socket
+        assert!(cidr("192.0.2.0/24").contains(ip("::ffff:192.0.2.1")));
+        assert!(!cidr("192.0.2.0/24").contains(ip("::ffff:198.51.100.1")));
+        assert!(cidr("192.0.2.1").contains(ip("::ffff:192.0.2.1")));
+
+        // an IPv4 range written as IPv6
+        let range = cidr("::ffff:192.0.2.0/104");
+        assert!(range.contains(ip("192.0.0.0")));
+        assert!(range.contains(ip("192.255.255.255")));
+        assert!(range.contains(ip("::ffff:192.0.2.1")));
+        assert!(!range.contains(ip("193.0.0.1")));
+        assert!(cidr("::ffff:192.0.2.1/128").contains(ip("192.0.2.1")));
+
+        // not mapped
+        assert!(!cidr("192.0.2.0/24").contains(ip("::192.0.2.1")));
+        assert!(!cidr("192.0.2.0/24").contains(ip("64:ff9b::192.0.2.1")));
+    }
+
+    #[test]
+    fn deny_wins() {
+        let rules = acl(&["10.0.0.0/8"], &["10.6.6.0/24"]);
+        assert!(rules.permits(Some(ip("10.0.0.1"))));
+        assert!(!rules.permits(Some(ip("10.6.6.6"))));
+        assert!(!rules.permits(Some(ip("::ffff:10.6.6.6"))));
+        assert!(!rules.permits(Some(ip("192.0.2.1"))));
+
+        let rules = acl(&["10.6.6.6"], &["10.0.0.0/8"]);
+        assert!(!rules.permits(Some(ip("10.6.6.6"))));
+    }
+
+    #[test]
+    fn lists() {
+        let rules = acl(&[], &[]);
+        assert!(rules.is_empty());
+        assert!(rules.permits(Some(ip("192.0.2.1"))));
+
+        let rules = acl(&[], &["192.0.2.0/24"]);
+        assert!(!rules.is_empty());
+        assert!(rules.permits(Some(ip("198.51.100.1"))));
+        assert!(!rules.permits(Some(ip("192.0.2.1"))));
+
+        let rules = acl(&["192.0.2.0/24", "2001:db8::/32"], &[]);
+        assert!(rules.permits(Some(ip("2001:db8::1"))));
+        assert!(!rules.permits(Some(ip("2001:db9::1"))));
+    }
+
+    #[test]
+    fn unix_clients() {
+        assert!(acl(&[], &[]).permits(None));
+        assert!(acl(&[], &["0.0.0.0/0", "::/0"]).permits(None));
+        assert!(!acl(&["0.0.0.0/0"], &[]).permits(None));
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        let proxies = [cidr("10.0.1.10"), cidr("2001:db8::/64")];
+        assert!(trusts(&proxies, Some(ip("10.0.1.10"))));
+        assert!(trusts(&proxies, Some(ip("::ffff:10.0.1.10"))));
+        assert!(trusts(&proxies, Some(ip("2001:db8::5"))));
+        assert!(!trusts(&proxies, Some(ip("10.0.1.11"))));
+        assert!(!trusts(&[], Some(ip("10.0.1.10"))));
+        assert!(trusts(&[], None));
+    }
+}
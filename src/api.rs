@@ -1,10 +1,12 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::channel;
 use std::sync::mpsc::Sender;
 use std::path::Path;
-use std::net::SocketAddr;
-use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
 use std::fs::File;
+use std::io::Read;
+use std::cmp;
 
 use hyper::Server;
 use hyper::server::Handler;
@@ -18,6 +20,7 @@ use hyper::net::Openssl;
 use hyper::header::ContentType;
 use hyper::mime::{Mime, TopLevel, SubLevel, Attr, Value};
 use hyper::uri::RequestUri;
+use hyper::method::Method;
 
 use openssl::ssl::{SslContext, SSL_OP_NO_TLSV1, SSL_OP_NO_TLSV1_1, SslMethod, SSL_VERIFY_PEER, SSL_VERIFY_FAIL_IF_NO_PEER_CERT};
 use openssl::x509::X509;
@@ -27,20 +30,54 @@ use openssl::ssl::error::SslError;
 use openssl::nid::Nid;
 
 use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
 
 use mio::Sender as MioSender;
 
 use rustc_serialize::json;
+use rustc_serialize::json::Json;
+use rustc_serialize::Encodable;
+
+use config::validate_destination;
+use multiplex::{route_server_name, validate_route};
+use fingerprint::Fingerprint;
+use acl::{Acl, Cidr};
+use pool::{Destinations, Pool, Strategy};
+use proxy;
+use reload::{Control, Reload};
 
 //const CIPHERS: &'static str = "DEFAULT";
 const CIPHERS: &'static str = "AES256-GCM-SHA384:AES256-SHA256:AES256-SHA:CAMELLIA256-SHA";
 
-pub type SniMap = BTreeMap<String, String>;
+// the SNI under which each endpoint routes to its own API
+pub const API_SNI: &'static str = "tnexus.net";
+
+const ROUTES: &'static str = "/routes";
+const ROUTES_PREFIX: &'static str = "/routes/";
+
+// max size of a request body
+const MAX_BODY: u64 = 65536;
+
+pub type SniMap = BTreeMap<String, Destinations>;
 
 pub enum ApiMsg {
-    SniRequest(Sender<ApiMsg>),
+    SniRequest(String, Sender<ApiMsg>),
     SniResponse(SniMap),
+    StatsRequest(String, Sender<ApiMsg>),
+    StatsResponse(Stats),
+    FlowsRequest(String, Sender<ApiMsg>),
+    FlowsResponse(Vec<FlowInfo>),
+    HealthRequest(String, Sender<ApiMsg>),
+    HealthResponse(Vec<BackendInfo>),
+    RouteSet(String, SniMap, Sender<ApiMsg>),
+    RouteDelete(String, String, Sender<ApiMsg>),
+    // whether the route existed before, or an error
+    RouteResponse(Result<bool, String>),
+    Reload(Reload, Sender<ApiMsg>),
+    ReloadResponse(Result<(), String>),
     Quit(Sender<ApiMsg>),
+    // from a resolver thread to its worker (see dns::Resolver)
+    Resolved(String, Result<Vec<IpAddr>, String>),
 }
 
 #[derive(RustcEncodable)]
@@ -48,53 +85,138 @@ pub struct ExampleResponse {
     map: SniMap,
 }
 
+#[derive(RustcEncodable, Debug, Default)]
+pub struct Stats {
+    // number of active flows
+    pub connections: usize,
+    // of the config (not the share of a worker)
+    pub max_connections: usize,
+    // number of inbound connections refused because max_connections was reached
+    pub refused: usize,
+    // number of inbound connections dropped because the client is not allowed (by the listener or a route)
+    pub denied: usize,
+}
+
+#[derive(RustcEncodable, Debug)]
+pub struct FlowInfo {
+    pub client: Option<String>,
+    // current destination (the one being connected to, if not connected yet)
+    pub destination: Option<String>,
+    pub fingerprint: Option<Fingerprint>,
+}
+
+#[derive(RustcEncodable, Debug)]
+pub struct BackendInfo {
+    pub backend: String,
+    // in rotation, i.e. neither ejected nor failing its health checks
+    pub healthy: bool,
+    // outcome of the last health check, "up" or "down: <reason>" (None if not checked)
+    pub check: Option<String>,
+    // consecutive failures of flows to connect
+    pub fails: usize,
+    // out of rotation after max_fails consecutive failures
+    pub ejected: bool,
+    pub flows: usize,
+}
+
+impl Stats {
+    fn add(&mut self, other: &Stats) {
+        self.connections += other.connections;
+        // the same for every worker
+        self.max_connections = other.max_connections;
+        self.refused += other.refused;
+        self.denied += other.denied;
+    }
+}
+
+impl BackendInfo {
+    /// Every worker checks and counts on its own: the backend is healthy if it is for all of them.
+    fn add(&mut self, other: BackendInfo) {
+        self.healthy &= other.healthy;
+        if other.check.as_ref().map_or(false, |check| check != "up") || self.check.is_none() {
+            self.check = other.check;
+        }
+        self.fails = cmp::max(self.fails, other.fails);
+        self.ejected |= other.ejected;
+        self.flows += other.flows;
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Api {
+    // name of the [[listen]] section this API belongs to
+    listener: String,
     key_path: String,
     cert_path: String,
     client_cert_path: String,
-    main_channel: MioSender<ApiMsg>,
+    // one channel per worker event loop
+    workers: Vec<MioSender<ApiMsg>>,
+    // main thread, for reloading the configuration
+    control: Arc<Mutex<Sender<Control>>>,
+    // set once the [[listen]] section is gone, see RunningApi::close
+    closed: Arc<AtomicBool>,
 }
 
 #[derive(Debug)]
 pub struct RunningApi {
     api: Api,
     pub local_addr: SocketAddr,
-    pub thread_handle: Listening,
+    thread_handle: Listening,
 }
 
 impl Api {
-    pub fn new(key: &str, cert: &str, client_cert: &str, main_channel: MioSender<ApiMsg>) -> Api {
+    pub fn new(listener: &str, key: &str, cert: &str, client_cert: &str, workers: Vec<MioSender<ApiMsg>>,
+               control: Sender<Control>) -> Api {
         Api {
+            listener: listener.to_string(),
             key_path: key.to_string(),
             cert_path: cert.to_string(),
             client_cert_path: client_cert.to_string(),
-            main_channel: main_channel,
+            workers: workers,
+            control: Arc::new(Mutex::new(control)),
+            closed: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    pub fn spawn(self) -> RunningApi {
+    pub fn spawn(self) -> Result<RunningApi, String> {
         //let api = Api::new("api_key.pem", "api_cert.pem", "client_cert.pem");
         info!("Starting api endpoint: {:?}", self);
         //Openssl::with_cert_and_key
-        let ssl = ctx(&self.cert_path, &self.key_path, path2pem(&self.client_cert_path))
-        		.ok().expect("Could not initialize SSL context");
-        let mut listener = HttpsListener::new("127.0.0.1:0", ssl)
-                .expect("Could not initialize API listener");
+        let ssl = try!(load_tls(&self.key_path, &self.cert_path, &self.client_cert_path));
+        let mut listener = try!(HttpsListener::new("127.0.0.1:0", ssl)
+                .map_err(|e| format!("Could not initialize API listener, {}", e)));
         let local_addr = {
             let l2_ref = &mut listener;
-            l2_ref.local_addr().expect("Could not determine API listener port")
+            try!(l2_ref.local_addr().map_err(|e| format!("Could not determine API listener port, {}", e)))
         };
         let server = Server::new(listener);
         info!("API listeneing on {:?}", local_addr);
     
         let self_clone = self.clone();
-        let listening = server.handle_threads(self_clone, 1).unwrap();
+        let listening = try!(server.handle_threads(self_clone, 1).map_err(|e| format!("Could not start API, {}", e)));
         
-        RunningApi {
+        Ok(RunningApi {
             api: self,
             local_addr: local_addr,
             thread_handle: listening,
+        })
+    }
+}
+
+impl RunningApi {
+    /// Name of the [[listen]] section this API belongs to.
+    pub fn listener(&self) -> &str {
+        &self.api.listener
+    }
+
+    /// Stop serving requests.
+    ///
+    /// hyper keeps its listening thread (and port) around regardless, so from now on it
+    /// answers every request with 503.
+    pub fn close(&mut self) {
+        self.api.closed.store(true, Ordering::SeqCst);
+        if let Err(e) = self.thread_handle.close() {
+            warn!("[{}] Failed to close the API listener, {}", self.api.listener, e);
         }
     }
 }
@@ -104,39 +226,352 @@ fn respond(mut res: Response, code: StatusCode, body: &[u8]) {
     res.send(body).ok().expect("Could not send response!");
 }
 
+fn respond_json<T: Encodable>(mut res: Response, object: &T) {
+    let encoded = json::encode(object).unwrap();
+
+    res.headers_mut().set(ContentType(Mime(
+            TopLevel::Application, 
+            SubLevel::Json, 
+            vec![(Attr::Charset, Value::Utf8)])));
+
+    respond(res, StatusCode::Ok, encoded.as_bytes());
+}
+
+/// Reads a JSON request body.
+fn read_json(req: &mut Request) -> Result<Json, String> {
+    let mut body = String::new();
+    try!(req.by_ref().take(MAX_BODY).read_to_string(&mut body)
+        .map_err(|e| format!("Could not read request body: {}", e)));
+    Json::from_str(&body).map_err(|e| format!("Invalid JSON: {}", e))
+}
+
+/// Reads the destinations of a route, given as an "ip:port" (or "host:port" or "unix:/path") string, an array of them
+/// or a pool like {"backends": ["ip:port", ...], "strategy": "weighted", "weights": [3, 1]}
+/// (optionally with "send_proxy_protocol": "v1" or "v2", and "allow" and "deny" arrays of address ranges).
+fn to_destinations(json: &Json) -> Result<Destinations, String> {
+    let pool = match *json {
+        Json::Object(ref pool) => pool,
+        _ => return to_backends(json).map(Destinations::new),
+    };
+
+    let backends = try!(pool.get("backends").ok_or("Missing backends of pool".to_string()).and_then(to_backends));
+    let strategy = match pool.get("strategy") {
+        Some(&Json::String(ref name)) => try!(Strategy::parse(name)),
+        Some(strategy) => return Err(format!("Bad strategy {}", strategy)),
+        None => Strategy::RoundRobin,
+    };
+    let weights = match pool.get("weights") {
+        Some(&Json::Array(ref array)) => {
+            let mut weights = Vec::new();
+            for weight in array.iter() {
+                match weight.as_u64() {
+                    Some(weight) => weights.push(weight as usize),
+                    None => return Err(format!("Bad weight {}", weight)),
+                }
+            }
+            weights
+        },
+        Some(weights) => return Err(format!("Bad weights {}, expected an array of positive integers", weights)),
+        None => Vec::new(),
+    };
+    let send_proxy = match pool.get("send_proxy_protocol") {
+        Some(&Json::String(ref name)) => Some(try!(proxy::Version::parse(name))),
+        Some(version) => return Err(format!("Bad PROXY protocol version {}", version)),
+        None => None,
+    };
+
+    let acl = Acl {
+        allow: try!(to_cidrs(pool.get("allow"))),
+        deny: try!(to_cidrs(pool.get("deny"))),
+    };
+
+    Destinations::pool(backends, strategy, weights, send_proxy, acl)
+}
+
+fn to_cidrs(json: Option<&Json>) -> Result<Vec<Cidr>, String> {
+    let mut result = Vec::new();
+    match json {
+        Some(&Json::Array(ref array)) => {
+            for cidr in array.iter() {
+                match cidr.as_string() {
+                    Some(cidr) => result.push(try!(Cidr::parse(cidr))),
+                    None => return Err(format!("Bad address range {}", cidr)),
+                }
+            }
+        },
+        Some(cidrs) => return Err(format!("Bad address ranges {}, expected an array of strings", cidrs)),
+        None => (),
+    }
+    Ok(result)
+}
+
+fn to_backends(json: &Json) -> Result<Vec<String>, String> {
+    let destinations: Vec<String> = match *json {
+        Json::String(ref destination) => vec![destination.clone()],
+        Json::Array(ref array) if !array.is_empty() => {
+            let mut result = Vec::new();
+            for destination in array.iter() {
+                match destination.as_string() {
+                    Some(destination) => result.push(destination.to_string()),
+                    None => return Err(format!("Bad destination {}", destination)),
+                }
+            }
+            result
+        },
+        _ => return Err(format!("Bad destination {}, expected \"ip:port\", \"host:port\" or \"unix:/path\", an array of them or a pool", json)),
+    };
+
+    for destination in destinations.iter() {
+        try!(validate_destination(destination));
+    }
+
+    Ok(destinations)
+}
+
+/// Decodes the %XX escapes of a path segment, e.g. of a route like "~^api[0-9]+\\.example\\.com$".
+fn percent_decode(segment: &str) -> Result<String, String> {
+    let bytes = segment.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let digit = |at: usize| bytes.get(at).and_then(|b| (*b as char).to_digit(16));
+            match (digit(i + 1), digit(i + 2)) {
+                (Some(high), Some(low)) => result.push((high * 16 + low) as u8),
+                _ => return Err(format!("Bad escape in {}", segment)),
+            }
+            i += 3;
+        } else {
+            result.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(result).map_err(|_| format!("Bad UTF-8 in {}", segment))
+}
+
+/// Reads a set of routes, given as a JSON object of SNI to destinations.
+/// Whether a route is the one of the API (whatever the case, trailing dot or ALPN protocol),
+/// which would otherwise be replaced, removed or beaten by it.
+fn is_api_route(name: &str) -> bool {
+    route_server_name(name) == API_SNI
+}
+
+/// Checks every route like a worker would set it, before any worker gets them: workers set
+/// routes one at a time, so they would be left with the ones before a bad one.
+fn check_routes(routes: &SniMap) -> Result<(), String> {
+    for (name, destinations) in routes.iter() {
+        try!(validate_route(name));
+        try!(Pool::new(destinations.clone()));
+    }
+    Ok(())
+}
+
+fn to_routes(json: &Json) -> Result<SniMap, String> {
+    match *json {
+        Json::Object(ref object) => {
+            let mut routes = BTreeMap::new();
+            for (sni, destinations) in object.iter() {
+                routes.insert(sni.clone(), try!(to_destinations(destinations)));
+            }
+            Ok(routes)
+        },
+        _ => Err("Expected an object of SNI to destinations".to_string()),
+    }
+}
+
+impl Api {
+    /// Sends a request to every worker event loop and collects their responses.
+    fn ask_workers<F>(&self, request: F) -> Result<Vec<ApiMsg>, ()>
+            where F: Fn(Sender<ApiMsg>) -> ApiMsg {
+        let (tx, rx) = channel();
+
+        for worker in self.workers.iter() {
+            if worker.send(request(tx.clone())).is_err() {
+                error!("Error talking to worker event_loop");
+                return Err(());
+            }
+        }
+
+        let mut responses = Vec::new();
+        for _ in self.workers.iter() {
+            match rx.recv() {
+                Ok(response) => responses.push(response),
+                Err(_) => return Err(()),
+            }
+        }
+
+        Ok(responses)
+    }
+
+    fn respond_map(&self, res: Response) {
+        match self.ask_workers(|tx| ApiMsg::SniRequest(self.listener.clone(), tx)) {
+            Err(_) => {
+                respond(res, StatusCode::InternalServerError, b"Error talking to main event_loop");
+            },
+            Ok(responses) => {
+                // all workers route the same way, merging is just for good measure
+                let mut sni_map = BTreeMap::new();
+                for response in responses {
+                    if let ApiMsg::SniResponse(rex) = response {
+                        sni_map.extend(rex);
+                    }
+                }
+
+                let object = ExampleResponse {
+                    map: sni_map,
+                };
+
+                respond_json(res, &object);
+            },
+        }
+    }
+
+    /// Sends a route change to every worker, and responds with the resulting map.
+    fn change_routes<F>(&self, res: Response, request: F) where F: Fn(Sender<ApiMsg>) -> ApiMsg {
+        match self.ask_workers(request) {
+            Err(_) => {
+                respond(res, StatusCode::InternalServerError, b"Error talking to main event_loop");
+            },
+            Ok(responses) => {
+                let mut existed = false;
+                for response in responses {
+                    match response {
+                        ApiMsg::RouteResponse(Ok(result)) => existed |= result,
+                        ApiMsg::RouteResponse(Err(e)) => {
+                            respond(res, StatusCode::BadRequest, e.as_bytes());
+                            return;
+                        },
+                        _ => (),
+                    }
+                }
+
+                if existed {
+                    self.respond_map(res);
+                } else {
+                    respond(res, StatusCode::NotFound, b"No such route");
+                }
+            },
+        }
+    }
+}
+
 impl Handler for Api {
-    fn handle(&self, req: Request, mut res: Response) {
-        match req.uri {
+    fn handle(&self, mut req: Request, res: Response) {
+        if self.closed.load(Ordering::SeqCst) {
+            return respond(res, StatusCode::ServiceUnavailable, b"The [[listen]] section of this API is gone");
+        }
+        match req.uri.clone() {
             RequestUri::AbsolutePath(path) => {
-                info!("{}", path);
-                let (tx, rx) = channel();
-                if path == "/" {
-                    let send_result = self.main_channel.send(ApiMsg::SniRequest(tx));
-                    if send_result.is_err() {
-                        println!("Error talking to main event_loop");
-                        respond(res, StatusCode::InternalServerError, b"Error talking to main event_loop");
+                info!("{} {}", req.method, path);
+                if path == "/" || (path == ROUTES && req.method == Method::Get) {
+                    self.respond_map(res);
+                } else if path == ROUTES && req.method == Method::Post {
+                    match read_json(&mut req).and_then(|json| to_routes(&json))
+                            .and_then(|routes| check_routes(&routes).map(|_| routes)) {
+                        Err(e) => respond(res, StatusCode::BadRequest, e.as_bytes()),
+                        Ok(ref routes) if routes.keys().any(|name| is_api_route(name)) => {
+                            respond(res, StatusCode::Forbidden, b"The API route cannot be changed");
+                        },
+                        Ok(routes) => {
+                            self.change_routes(res, |tx| ApiMsg::RouteSet(self.listener.clone(), routes.clone(), tx));
+                        },
+                    }
+                } else if path.starts_with(ROUTES_PREFIX) {
+                    let sni = match percent_decode(&path[ROUTES_PREFIX.len()..]) {
+                        Ok(sni) => sni,
+                        Err(e) => return respond(res, StatusCode::BadRequest, e.as_bytes()),
+                    };
+                    if sni.is_empty() {
+                        respond(res, StatusCode::NotFound, b"No such route");
+                    } else if is_api_route(&sni) {
+                        respond(res, StatusCode::Forbidden, b"The API route cannot be changed");
+                    } else if req.method == Method::Put {
+                        let routes = read_json(&mut req).and_then(|json| to_destinations(&json)).map(|destinations| {
+                            let mut routes = BTreeMap::new();
+                            routes.insert(sni, destinations);
+                            routes
+                        });
+                        match routes.and_then(|routes| check_routes(&routes).map(|_| routes)) {
+                            Err(e) => respond(res, StatusCode::BadRequest, e.as_bytes()),
+                            Ok(routes) => {
+                                self.change_routes(res, |tx| ApiMsg::RouteSet(self.listener.clone(), routes.clone(), tx));
+                            },
+                        }
+                    } else if req.method == Method::Delete {
+                        self.change_routes(res, |tx| ApiMsg::RouteDelete(self.listener.clone(), sni.clone(), tx));
                     } else {
-                        let response: ApiMsg = rx.recv().unwrap();
-                        let sni_map = match response {
-                            ApiMsg::SniResponse(rex) => rex,
-                            _ => BTreeMap::new(),
-                        };
-            
-            			let object = ExampleResponse {
-            			    map: sni_map,
-            			};
-                        
-                        let encoded = json::encode(&object).unwrap();
-                        
-                        res.headers_mut().set(ContentType(Mime(
-                                TopLevel::Application, 
-                                SubLevel::Json, 
-                                vec![(Attr::Charset, Value::Utf8)])));
-            
-                        respond(res, StatusCode::Ok, encoded.as_bytes());
+                        respond(res, StatusCode::MethodNotAllowed, b"Use PUT or DELETE");
+                    }
+                } else if path == "/stats" {
+                    match self.ask_workers(|tx| ApiMsg::StatsRequest(self.listener.clone(), tx)) {
+                        Err(_) => {
+                            respond(res, StatusCode::InternalServerError, b"Error talking to main event_loop");
+                        },
+                        Ok(responses) => {
+                            let mut stats = Stats::default();
+                            for response in responses {
+                                if let ApiMsg::StatsResponse(worker_stats) = response {
+                                    stats.add(&worker_stats);
+                                }
+                            }
+
+                            respond_json(res, &stats);
+                        },
+                    }
+                } else if path == "/flows" {
+                    match self.ask_workers(|tx| ApiMsg::FlowsRequest(self.listener.clone(), tx)) {
+                        Err(_) => {
+                            respond(res, StatusCode::InternalServerError, b"Error talking to main event_loop");
+                        },
+                        Ok(responses) => {
+                            let mut flows = Vec::new();
+                            for response in responses {
+                                if let ApiMsg::FlowsResponse(worker_flows) = response {
+                                    flows.extend(worker_flows);
+                                }
+                            }
+
+                            respond_json(res, &flows);
+                        },
+                    }
+                } else if path == "/health" {
+                    match self.ask_workers(|tx| ApiMsg::HealthRequest(self.listener.clone(), tx)) {
+                        Err(_) => {
+                            respond(res, StatusCode::InternalServerError, b"Error talking to main event_loop");
+                        },
+                        Ok(responses) => {
+                            let mut backends: BTreeMap<String, BackendInfo> = BTreeMap::new();
+                            for response in responses {
+                                if let ApiMsg::HealthResponse(worker_backends) = response {
+                                    for info in worker_backends {
+                                        match backends.entry(info.backend.clone()) {
+                                            Entry::Occupied(mut merged) => merged.get_mut().add(info),
+                                            Entry::Vacant(entry) => {
+                                                entry.insert(info);
+                                            },
+                                        }
+                                    }
+                                }
+                            }
+
+                            let backends: Vec<BackendInfo> = backends.into_iter().map(|(_, info)| info).collect();
+                            respond_json(res, &backends);
+                        },
+                    }
+                } else if path == "/reload" && req.method == Method::Post {
+                    let (tx, rx) = channel();
+                    let send_result = self.control.lock().ok()
+                        .and_then(|control| control.send(Control::Reload(Some(tx))).ok());
+                    match send_result.and_then(|_| rx.recv().ok()) {
+                        None => respond(res, StatusCode::InternalServerError, b"Error talking to main thread"),
+                        Some(Ok(_)) => respond(res, StatusCode::Ok, b"Reloaded"),
+                        Some(Err(e)) => respond(res, StatusCode::BadRequest, e.as_bytes()),
                     }
                 } else if path == "/quit" {
-                    let send_result = self.main_channel.send(ApiMsg::Quit(tx));
+                    let (tx, _rx) = channel();
+                    let send_result = self.workers.iter()
+                        .map(|worker| worker.send(ApiMsg::Quit(tx.clone())))
+                        .fold(Ok(()), |acc, result| acc.and(result.map_err(|_| ())));
                     if send_result.is_err() {
                         println!("Error sending Quit to main event_loop");
                         respond(res, StatusCode::InternalServerError, b"Error sending Quit to main event_loop");
@@ -157,6 +592,17 @@ impl Handler for Api {
     }
 }
 
+/// The TLS context of an API: its key and certificate, and the only client certificate it accepts.
+///
+/// Also checks (e.g. when loading the config) that the files are there and make sense.
+pub fn load_tls(key: &str, cert: &str, client_cert: &str) -> Result<Openssl, String> {
+    for path in [key, cert].iter() {
+        try!(File::open(path).map_err(|e| format!("Could not open {}, {}", path, e)));
+    }
+    let client_cert = try!(path2pem(client_cert));
+    ctx(cert, key, client_cert).map_err(|e| format!("Could not load {} and {}, {}", cert, key, e))
+}
+
 fn ctx<C, K>(cert: C, key: K, client_cert: Vec<u8>) -> Result<Openssl, SslError> 
         where C: AsRef<Path>, K: AsRef<Path> {
     
@@ -197,22 +643,70 @@ fn verifier(_preverify_ok: bool, x509_ctx: &X509StoreContext, client_cert: &Vec<
     }
 }
 
-fn path2pem(path1: &str) -> Vec<u8> {
+fn path2pem(path1: &str) -> Result<Vec<u8>, String> {
     let path = Path::new(path1);
 
     let mut file = match File::open(&path) {
-        // The `description` method of `io::Error` returns a string that
-        // describes the error
-        Err(why) => {
-            let display = path.display();
-            panic!("couldn't open {}: {}", display, Error::description(&why))
-        },
+        Err(why) => return Err(format!("Could not open {}, {}", path.display(), why)),
         Ok(file) => file,
     };
 
-    let cert = X509::from_pem(&mut file).expect("Unable to parse PEM"); //format!("Failed to parse pem {:?}", path1)
+    let cert = try!(X509::from_pem(&mut file).map_err(|e| format!("Unable to parse PEM in {}, {}", path1, e)));
 	let mut buf = Vec::new();
-    cert.write_pem(&mut buf).expect("Unable to serialize PEM");
+    try!(cert.write_pem(&mut buf).map_err(|e| format!("Unable to serialize PEM of {}, {}", path1, e)));
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_routes, is_api_route, percent_decode, SniMap};
+    use pool::Destinations;
+
+    #[test]
+    fn decodes_route_names() {
+        assert_eq!(percent_decode("www.example.com").unwrap(), "www.example.com");
+        assert_eq!(percent_decode("%2A.example.com%40h2").unwrap(), "*.example.com@h2");
+        assert_eq!(percent_decode("%7E%5Eapi%5B0-9%5D%2B%5C.example%5C.com%24").unwrap(), "~^api[0-9]+\\.example\\.com$");
+        assert_eq!(percent_decode("caf%C3%A9").unwrap(), "caf\u{e9}");
+    }
 
-    buf
+    #[test]
+    fn rejects_bad_escapes() {
+        assert!(percent_decode("%").is_err());
+        assert!(percent_decode("a%2").is_err());
+        assert!(percent_decode("%zz").is_err());
+        assert!(percent_decode("%+f").is_err());
+        assert!(percent_decode("%FF").is_err());
+    }
+
+    #[test]
+    fn protects_the_api_route() {
+        assert!(is_api_route("tnexus.net"));
+        assert!(is_api_route("TNEXUS.NET"));
+        assert!(is_api_route("tnexus.net."));
+        assert!(is_api_route("tnexus.net@h2"));
+        assert!(is_api_route("TNexus.net.@http/1.1"));
+
+        assert!(!is_api_route("www.tnexus.net"));
+        assert!(!is_api_route("*.tnexus.net"));
+        assert!(!is_api_route("tnexus.network"));
+    }
+
+    #[test]
+    fn checks_every_route() {
+        let mut routes = SniMap::new();
+        routes.insert("a.example.com".to_string(), Destinations::new(vec!["127.0.0.1:8443".to_string()]));
+        routes.insert("*.example.com@h2".to_string(), Destinations::new(vec!["unix:/run/backend.sock".to_string()]));
+        routes.insert("~^api[0-9]+\\.example\\.com$".to_string(), Destinations::new(vec!["backend:443".to_string()]));
+        assert!(check_routes(&routes).is_ok());
+
+        let mut bad_name = routes.clone();
+        bad_name.insert("~^api[".to_string(), Destinations::new(vec!["127.0.0.1:8443".to_string()]));
+        assert!(check_routes(&bad_name).is_err());
+
+        let mut bad_backend = routes.clone();
+        bad_backend.insert("z.example.com".to_string(), Destinations::new(vec!["127.0.0.1".to_string()]));
+        assert!(check_routes(&bad_backend).is_err());
+    }
 }
@@ -0,0 +1,147 @@
+use std::env::args;
+use std::io;
+use std::io::Write;
+use std::process;
+
+use log::LogLevelFilter;
+
+use config::{Overload, Overrides};
+
+const USAGE: &'static str = "\
+Usage: tnexus [run] [options]
+       tnexus check-config [--config <path>]
+       tnexus print-default-config
+       tnexus <listen_ip:port> <destination_ip:port> [alternate_ip:port...]
+
+Subcommands:
+  run                        forward connections (the default)
+  check-config               report every problem of the config file and exit
+                             (non-zero if there are any)
+  print-default-config       print a sample config file and exit
+
+Options:
+  -c, --config <path>        config file (default: tnexus.toml)
+  --listen <ip:port>         forward connections accepted on this endpoint...
+  --forward <ip:port>        ...to this destination (repeat for alternates).
+                             Without --config, no config file is read.
+                             Either may also be unix:/path, and a destination
+                             host:port.
+  --workers <n>              override [global] workers
+  --max-connections <n>      override [global] max_connections
+  --max-connections-policy <reject|backlog>
+                             override [global] max_connections_policy
+  --bufsize <n>              override the bufsize of every [[listen]] section
+  --log-level <level>        off, error, warn, info, debug or trace
+                             (default: as per RUST_LOG)
+  -V, --version              print the version and exit
+  -h, --help                 print this help and exit
+";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run,
+    CheckConfig,
+    PrintDefaultConfig,
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub command: Command,
+    // config file given with --config
+    pub config: Option<String>,
+    pub log_level: Option<LogLevelFilter>,
+    // settings that take precedence over the config file
+    pub overrides: Overrides,
+}
+
+/// Parses the command line, exiting on --help, --version and bad arguments.
+pub fn parse() -> Options {
+    let args: Vec<String> = args().skip(1).collect();
+
+    match parse_args(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            let _ = writeln!(&mut io::stderr(), "tnexus: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        },
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        command: Command::Run,
+        config: None,
+        log_level: None,
+        overrides: Overrides::default(),
+    };
+
+    let mut listen = None;
+    let mut forward = Vec::new();
+    let mut positional = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value", arg));
+
+        match &arg[..] {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                process::exit(0);
+            },
+            "-V" | "--version" => {
+                println!("tnexus {}", env!("CARGO_PKG_VERSION"));
+                process::exit(0);
+            },
+            "-c" | "--config" => options.config = Some(try!(value()).clone()),
+            // the original spelling of check-config
+            "--check-config" => {
+                options.command = Command::CheckConfig;
+                options.config = Some(try!(value()).clone());
+            },
+            "--listen" => listen = Some(try!(value()).clone()),
+            "--forward" => forward.push(try!(value()).clone()),
+            "--workers" => options.overrides.workers = Some(try!(positive_int(arg, try!(value())))),
+            "--max-connections" => options.overrides.max_connections = Some(try!(positive_int(arg, try!(value())))),
+            "--max-connections-policy" => options.overrides.overload = match &try!(value())[..] {
+                "reject" => Some(Overload::Reject),
+                "backlog" => Some(Overload::Backlog),
+                policy => return Err(format!("{} should be reject or backlog, not {}", arg, policy)),
+            },
+            "--bufsize" => options.overrides.bufsize = Some(try!(positive_int(arg, try!(value())))),
+            "--log-level" => {
+                let level = try!(value());
+                options.log_level = Some(try!(level.parse().map_err(|_| format!("Unknown log level {}", level))));
+            },
+            "run" if positional.is_empty() => options.command = Command::Run,
+            "check-config" if positional.is_empty() => options.command = Command::CheckConfig,
+            "print-default-config" if positional.is_empty() => options.command = Command::PrintDefaultConfig,
+            _ if arg.starts_with("-") => return Err(format!("Unknown option {}", arg)),
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    // tnexus listen_ip:port destination_ip:port [alternate_ip:port...]
+    if !positional.is_empty() {
+        if positional.len() < 2 || listen.is_some() {
+            return Err(format!("Unexpected argument {}", positional[0]));
+        }
+        listen = Some(positional.remove(0));
+        forward.extend(positional);
+    }
+
+    match (listen, forward.is_empty()) {
+        (Some(listen), false) => options.overrides.forward = Some((listen, forward)),
+        (Some(_), true) => return Err("--listen requires --forward".to_string()),
+        (None, false) => return Err("--forward requires --listen".to_string()),
+        (None, true) => (),
+    }
+
+    Ok(options)
+}
+
+fn positive_int(arg: &str, value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("{} should be a positive integer, not {}", arg, value)),
+    }
+}
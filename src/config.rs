@@ -1,147 +1,963 @@
-use std::env::args;
 use std::fs::File;
-use std::io::Read;
+use std::io;
+use std::io::{Read, Write};
+use std::fmt;
+use std::process;
 use std::collections::BTreeMap;
+use std::sync::mpsc::Sender as MpscSender;
 
 use mio::Sender;
 
 use toml::{Parser, Value, Table};
 
+use acl::{Acl, Cidr};
 use api::Api;
 use api::RunningApi;
 use api::ApiMsg;
-use multiplex::{Multiplexer, FixedPlexer, SniPlexer};
+use api::API_SNI;
+use api::load_tls;
+use multiplex::{Multiplexer, FixedPlexer, SniPlexer, HttpHostPlexer, ProtocolPlexer, validate_route, validate_host_route};
+use fingerprint;
+use pool::{Backend, Destinations, Strategy};
+use proxy;
+use dns::DNS_TTL;
+use stream::{Addr, UNIX_PREFIX};
+use health::{Check, HealthCheck, MAX_FAILS, FAIL_TIMEOUT, CHECK_INTERVAL, CHECK_TIMEOUT};
+use reload::Control;
+use tls::MAX_HELLO_SIZE;
+use http::MAX_HEADER_SIZE;
+use protocol::{PROTOCOLS, WAIT_TIMEOUT};
+use MAX_LISTENERS;
 
 const BUF_SIZE: usize = 1048576;
+const MAX_CONNECTIONS: usize = 1024;
+const WORKERS: usize = 1;
 
+pub const CONFIG_FILE: &'static str = "tnexus.toml";
+
+// name of the [[listen]] section made up from --listen/--forward
+const CLI_LISTENER: &'static str = "cli";
+
+/// The documented sample config.
+pub const DEFAULT_CONFIG: &'static str = include_str!("../tnexus.toml");
+
+/// A problem found in the configuration.
 #[derive(Debug)]
+pub enum ConfigError {
+    /// The config file could not be read.
+    Io(io::Error),
+    /// The config file is not valid TOML (line and column start at 1).
+    Syntax { line: usize, col: usize, desc: String },
+    /// A value is missing, has the wrong type or makes no sense.
+    /// The key is its path in the file, e.g. listen[0].sni_missing
+    Invalid { key: String, desc: String },
+}
+
+impl ConfigError {
+    fn invalid<S: Into<String>>(key: &str, desc: S) -> ConfigError {
+        ConfigError::Invalid { key: key.to_string(), desc: desc.into() }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref e) => write!(f, "could not read file: {}", e),
+            ConfigError::Syntax { line, col, ref desc } => write!(f, "line {}, column {}: {}", line, col, desc),
+            ConfigError::Invalid { ref key, ref desc } => write!(f, "{}: {}", key, desc),
+        }
+    }
+}
+
+/// What to do with new inbound connections once max_connections flows are active.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overload {
+    /// Accept the connection and immediately reset it (RST).
+    Reject,
+    /// Stop accepting, leaving new connections in the listen backlog until a flow ends.
+    Backlog,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    // the file this config was read from (None for command-line arguments)
+    pub file: Option<String>,
+    // number of event loops (each running on its own thread)
+    pub workers: usize,
+    pub max_connections: usize,
+    pub overload: Overload,
+    // consecutive connect failures that take a backend out of rotation
+    pub max_fails: usize,
+    // for how long, in ms
+    pub fail_timeout: u64,
+    // how long the addresses of host names (in destinations) are cached, in ms
+    pub dns_ttl: u64,
+    pub endpoints: Vec<Endpoint>,
+    // command-line settings applied on top of the file (again when reloading)
+    pub overrides: Overrides,
+}
+
+/// Settings given on the command line, which take precedence over the config file.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub workers: Option<usize>,
+    pub max_connections: Option<usize>,
+    pub overload: Option<Overload>,
+    // bufsize of every endpoint
+    pub bufsize: Option<usize>,
+    // ad-hoc endpoint from --listen and --forward
+    pub forward: Option<(String, Vec<String>)>,
+}
+
+/// How an endpoint picks the destination of its inbound connections.
+#[derive(Debug, Clone)]
+pub enum Routing {
+    /// Forward everything to the same destination(s).
+    Fixed(Destinations),
+    /// Route TLS connections by SNI.
+    Sni {
+        default: Destinations,
+        mismatch: Option<Destinations>,
+        map: BTreeMap<String, Destinations>,
+        // max size of a ClientHello, which may span several TLS records
+        max_hello_size: usize,
+        fingerprints: fingerprint::Rules<Destinations>,
+    },
+    /// Route plaintext HTTP by the Host header.
+    Http {
+        default: Destinations,
+        map: BTreeMap<String, Destinations>,
+        // max size of the request line and headers
+        max_header_size: usize,
+    },
+    /// Route by the protocol of the first bytes, e.g. to share a port between TLS and SSH.
+    Protocol {
+        // by protocol name (see protocol::PROTOCOLS): Fixed, or Sni for tls and Http for http
+        protocols: BTreeMap<String, Routing>,
+        default: Option<Destinations>,
+        // how long to wait for the client to speak first, in ms
+        wait_timeout: u64,
+    },
+}
+
+impl Routing {
+    pub fn multiplexer(&self) -> Box<Multiplexer> {
+        match *self {
+            Routing::Fixed(ref destination) => Box::new(FixedPlexer::new(destination.clone())),
+            Routing::Sni { ref default, ref mismatch, ref map, max_hello_size, ref fingerprints } =>
+                Box::new(SniPlexer::new(default.clone(), mismatch.clone(), map.clone(), max_hello_size,
+                                        fingerprints.clone())),
+            Routing::Http { ref default, ref map, max_header_size } =>
+                Box::new(HttpHostPlexer::new(default.clone(), map.clone(), max_header_size)),
+            Routing::Protocol { ref protocols, ref default, wait_timeout } => {
+                let protocols = protocols.iter()
+                    .map(|(protocol, routing)| (protocol.clone(), routing.multiplexer()))
+                    .collect();
+                Box::new(ProtocolPlexer::new(protocols, default.clone(), wait_timeout))
+            },
+        }
+    }
+
+    /// The sizes of the first bytes that have to fit in the buffer, e.g. ("max_hello_size", 16384).
+    fn limits(&self) -> Vec<(&'static str, usize)> {
+        match *self {
+            Routing::Fixed(_) => Vec::new(),
+            Routing::Sni { max_hello_size, .. } => vec![("max_hello_size", max_hello_size)],
+            Routing::Http { max_header_size, .. } => vec![("max_header_size", max_header_size)],
+            Routing::Protocol { ref protocols, .. } => protocols.values().flat_map(|routing| routing.limits()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiConfig {
+    pub key: String,
+    pub cert: String,
+    pub authorized_cert: String,
+}
+
+#[derive(Debug, Clone)]
 pub struct Endpoint {
+    pub name: String,
     pub bufsize: usize,
     pub listen: String,
-    pub destination: Box<Multiplexer>,
-    pub api: Option<RunningApi>,
+    pub routing: Routing,
+    pub api: Option<ApiConfig>,
+    pub health_check: Option<HealthCheck>,
+    // whether clients start with a PROXY protocol header
+    pub accept_proxy: bool,
+    // the load balancers that may send it
+    pub trusted_proxies: Vec<Cidr>,
+    // clients that may connect
+    pub acl: Acl,
 }
 
-pub fn configure(main_channel: Sender<ApiMsg>) -> Endpoint {
+impl Endpoint {
+    /// Builds a multiplexer for this endpoint (every worker gets its own).
+    pub fn multiplexer(&self) -> Box<Multiplexer> {
+        self.routing.multiplexer()
+    }
+}
 
-    let mut input = String::new();
-    File::open(&"tnexus.toml").and_then(|mut f| {
-            f.read_to_string(&mut input)
-        }).unwrap();
+impl Config {
+    fn new(file: Option<String>) -> Config {
+        Config {
+            file: file,
+            workers: WORKERS,
+            max_connections: MAX_CONNECTIONS,
+            overload: Overload::Reject,
+            max_fails: MAX_FAILS,
+            fail_timeout: FAIL_TIMEOUT,
+            dns_ttl: DNS_TTL,
+            endpoints: Vec::new(),
+            overrides: Overrides::default(),
+        }
+    }
 
-    match Parser::new(&input).parse() {
-        None => {
-            let args: Vec<String> = args().collect();
-        
-            if args.len() < 3 {
-                panic!("Usage: {} listen_ip:port destination_ip:port", args[0]);
+    /// Applies the command-line settings on top of the ones from the config file.
+    pub fn apply(&mut self, overrides: Overrides) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+
+        self.workers = overrides.workers.unwrap_or(self.workers);
+        self.max_connections = overrides.max_connections.unwrap_or(self.max_connections);
+        self.overload = overrides.overload.unwrap_or(self.overload);
+        if let Some(bufsize) = overrides.bufsize {
+            for endpoint in self.endpoints.iter_mut() {
+                endpoint.bufsize = bufsize;
+                for (attr, size) in endpoint.routing.limits() {
+                    if size > bufsize {
+                        errors.push(ConfigError::invalid("--bufsize", format!("{} is less than {} of [{}] ({})",
+                                                                              bufsize, attr, endpoint.name, size)));
+                    }
+                }
             }
-        
-            Endpoint {
-                bufsize: BUF_SIZE,
-                listen: args[1].clone(),
-                destination: Box::new(FixedPlexer::new(&args[2])),
-                api: None,
+        }
+
+        if let Some((ref listen, ref forward)) = overrides.forward {
+            check(&mut errors, validate_endpoint(listen).map_err(|e| ConfigError::invalid("--listen", e)));
+            if let Some(other) = self.endpoints.iter().find(|e| e.listen == *listen || e.name == CLI_LISTENER) {
+                errors.push(ConfigError::invalid("--listen", format!("conflicts with [[listen]] section {}", other.name)));
             }
+            if self.endpoints.len() >= MAX_LISTENERS {
+                errors.push(ConfigError::invalid("--listen", format!("too many [[listen]] sections, the max is {}", MAX_LISTENERS)));
+            }
+            for destination in forward.iter() {
+                check(&mut errors, validate_destination(destination).map_err(|e| ConfigError::invalid("--forward", e)));
+            }
+
+            self.endpoints.push(Endpoint {
+                name: CLI_LISTENER.to_string(),
+                bufsize: overrides.bufsize.unwrap_or(BUF_SIZE),
+                listen: listen.clone(),
+                routing: Routing::Fixed(Destinations::new(forward.clone())),
+                api: None,
+                health_check: None,
+                accept_proxy: false,
+                trusted_proxies: Vec::new(),
+                acl: Acl::default(),
+            });
+        }
+
+        self.overrides = overrides;
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Each worker gets an equal share of max_connections.
+    pub fn worker_max_connections(&self) -> usize {
+        (self.max_connections + self.workers - 1) / self.workers
+    }
+}
+
+/// Spawns the API of every endpoint that has one.
+pub fn start_apis(config: &mut Config, workers: Vec<Sender<ApiMsg>>, control: MpscSender<Control>) -> Result<Vec<RunningApi>, String> {
+    let mut apis = Vec::new();
+    for endpoint in config.endpoints.iter_mut() {
+        if let Some(api) = try!(start_api(endpoint, workers.clone(), control.clone())) {
+            apis.push(api);
+        }
+    }
+    Ok(apis)
+}
+
+/// Spawns the API of the endpoint (if it has one).
+///
+/// The API talks to all workers, and gets routed to by its endpoint as SNI API_SNI.
+pub fn start_api(endpoint: &mut Endpoint, workers: Vec<Sender<ApiMsg>>, control: MpscSender<Control>)
+                 -> Result<Option<RunningApi>, String> {
+    let running_api = match endpoint.api {
+        Some(ref api_config) => {
+            let api = Api::new(&endpoint.name, &api_config.key, &api_config.cert,
+                               &api_config.authorized_cert, workers, control);
+            try!(api.spawn().map_err(|e| format!("[{}] {}", endpoint.name, e)))
         },
-    	Some(value) => parse_toml(&value, main_channel),
+        None => return Ok(None),
+    };
+
+    route_api(endpoint, &running_api);
+    Ok(Some(running_api))
+}
+
+/// Routes API_SNI of the endpoint to its (already running) API.
+pub fn route_api(endpoint: &mut Endpoint, api: &RunningApi) {
+    let routing = match endpoint.routing {
+        Routing::Protocol { ref mut protocols, .. } => protocols.get_mut("tls"),
+        ref mut routing => Some(routing),
+    };
+    if let Some(&mut Routing::Sni { ref mut map, .. }) = routing {
+        map.insert(API_SNI.to_string(), Destinations::new(vec![format!("127.0.0.1:{}", api.local_addr.port())]));
     }
+}
 
+/// Reads the config file and applies the command-line settings, exiting if there are any problems.
+pub fn configure(path: Option<&str>, overrides: Overrides) -> Config {
+    read(path, &overrides).unwrap_or_else(|errors| exit_with(&source(path, &overrides), &errors))
 }
 
-fn parse_toml(value: &Table, main_channel: Sender<ApiMsg>) -> Endpoint {
-    debug!("{:?}", value);
+/// Reports every problem of the config file, and exits (non-zero if there are any).
+pub fn check_config(path: Option<&str>, overrides: Overrides) -> ! {
+    let source = source(path, &overrides);
+    match read(path, &overrides) {
+        Ok(config) => {
+            println!("{}: OK, {} [[listen]] section(s)", source, config.endpoints.len());
+            process::exit(0);
+        },
+        Err(errors) => exit_with(&source, &errors),
+    }
+}
+
+/// An ad-hoc forward given without --config does not need a config file.
+fn read(path: Option<&str>, overrides: &Overrides) -> Result<Config, Vec<ConfigError>> {
+    let mut config = match path {
+        None if overrides.forward.is_some() => Config::new(None),
+        _ => try!(load(path.unwrap_or(CONFIG_FILE))),
+    };
+
+    try!(config.apply(overrides.clone()));
+    Ok(config)
+}
+
+fn source(path: Option<&str>, overrides: &Overrides) -> String {
+    match path {
+        None if overrides.forward.is_some() => "command line".to_string(),
+        _ => path.unwrap_or(CONFIG_FILE).to_string(),
+    }
+}
+
+fn exit_with(source: &str, errors: &[ConfigError]) -> ! {
+    let _ = writeln!(&mut io::stderr(), "{}", describe(source, errors));
+    process::exit(1);
+}
+
+/// One line per problem, prefixed with where the config came from.
+pub fn describe(source: &str, errors: &[ConfigError]) -> String {
+    errors.iter()
+        .map(|e| format!("{}: {}", source, e))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// (Re-)reads the given config file, reporting all problems found in it.
+pub fn load(path: &str) -> Result<Config, Vec<ConfigError>> {
+    let mut input = String::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_string(&mut input))
+        .map_err(|e| vec![ConfigError::Io(e)]));
+
+    let mut parser = Parser::new(&input);
+    match parser.parse() {
+        Some(value) => parse_toml(&value, path),
+        None => Err(parser.errors.iter().map(|e| {
+            let (line, col) = parser.to_linecol(e.lo);
+            ConfigError::Syntax { line: line + 1, col: col + 1, desc: e.desc.clone() }
+        }).collect()),
+    }
+}
 
-	let bufsize = match value.get("global") {
-	    Some(&Value::Table(ref t)) => match t.get("bufsize") {
-            Some(&Value::Integer(ref i)) => Some((*i) as usize),
-            _ => None,
+/// Keeps the error (if any) for reporting later.
+fn check<T>(errors: &mut Vec<ConfigError>, result: Result<T, ConfigError>) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(e) => {
+            errors.push(e);
+            None
         },
+    }
+}
+
+fn parse_toml(value: &Table, path: &str) -> Result<Config, Vec<ConfigError>> {
+    debug!("{:?}", value);
+
+    let mut errors = Vec::new();
+
+	let global = match value.get("global") {
+	    Some(&Value::Table(ref t)) => Some(t),
+	    Some(_) => {
+	        errors.push(ConfigError::invalid("global", "should be a table"));
+	        None
+	    },
         _ => None,
-	}.unwrap_or(BUF_SIZE);
+	};
+
+	let defaults = Defaults {
+	    bufsize: check(&mut errors, get_positive_int("bufsize", global, "global")).unwrap_or(None).unwrap_or(BUF_SIZE),
+	    max_hello_size: check(&mut errors, get_positive_int("max_hello_size", global, "global")).unwrap_or(None)
+	        .unwrap_or(MAX_HELLO_SIZE),
+	    max_header_size: check(&mut errors, get_positive_int("max_header_size", global, "global")).unwrap_or(None)
+	        .unwrap_or(MAX_HEADER_SIZE),
+	};
+	let workers = check(&mut errors, get_positive_int("workers", global, "global")).unwrap_or(None).unwrap_or(WORKERS);
+	let max_connections = check(&mut errors, get_positive_int("max_connections", global, "global")).unwrap_or(None)
+	    .unwrap_or(MAX_CONNECTIONS);
+
+	let policy = global.map_or(Ok(None), |t| get_str("max_connections_policy", t, "global"));
+	let overload = match check(&mut errors, policy).unwrap_or(None) {
+	    None => Overload::Reject,
+	    Some(ref policy) if policy == "reject" => Overload::Reject,
+	    Some(ref policy) if policy == "backlog" => Overload::Backlog,
+	    Some(policy) => {
+	        errors.push(ConfigError::invalid("global.max_connections_policy",
+	                                         format!("should be \"reject\" or \"backlog\", not \"{}\"", policy)));
+	        Overload::Reject
+	    },
+	};
+	let max_fails = check(&mut errors, get_positive_int("max_fails", global, "global")).unwrap_or(None).unwrap_or(MAX_FAILS);
+	let fail_timeout = check(&mut errors, get_positive_int("fail_timeout", global, "global")).unwrap_or(None)
+	    .map(|ms| ms as u64)
+	    .unwrap_or(FAIL_TIMEOUT);
+	let dns_ttl = check(&mut errors, get_positive_int("dns_ttl", global, "global")).unwrap_or(None)
+	    .map(|ms| ms as u64)
+	    .unwrap_or(DNS_TTL);
 	
-	match value.get("listen") {
-	    Some(&Value::Array(ref a)) => parse_listen(bufsize, a, main_channel),
-	    _ => panic!("[[listen]] sections not found in config file!")
+	let endpoints = match value.get("listen") {
+	    Some(&Value::Array(ref a)) => parse_listen(&defaults, a, &mut errors),
+	    _ => {
+	        errors.push(ConfigError::invalid("listen", "[[listen]] sections not found in config file"));
+	        Vec::new()
+	    },
+	};
+
+	if !errors.is_empty() {
+	    return Err(errors);
 	}
+
+	Ok(Config {
+	    file: Some(path.to_string()),
+	    workers: workers,
+	    max_connections: max_connections,
+	    overload: overload,
+	    max_fails: max_fails,
+	    fail_timeout: fail_timeout,
+	    dns_ttl: dns_ttl,
+	    endpoints: endpoints,
+	    overrides: Overrides::default(),
+	})
 }
 
-fn parse_listen(bufsize: usize, listens: &Vec<Value>, main_channel: Sender<ApiMsg>) -> Endpoint {
+/// The [global] settings that [[listen]] sections may override.
+struct Defaults {
+    bufsize: usize,
+    max_hello_size: usize,
+    max_header_size: usize,
+}
+
+fn parse_listen(defaults: &Defaults, listens: &Vec<Value>, errors: &mut Vec<ConfigError>) -> Vec<Endpoint> {
     if listens.is_empty() {
-        panic!("Invalid configuration file: empty [[listen]] section!");
+        errors.push(ConfigError::invalid("listen", "empty [[listen]] section"));
     }
-    
-	//TODO: currently only one listening endpoint is supported
-	//this limitation will be lifted in the future.
-    match listens[0] {
-		Value::Table(ref t) => {
-		    let name = get_str_attr("name", t);
-		    let endpoint = get_str_attr("endpoint", t);
-
-		    match t.get("multiplex") {
-		        Some(&Value::Table(ref mt)) => {
-		            let default = get_str_attr("sni_missing", t);
-		            let mut sni_map = get_sni_map(mt);
-
-                    let api_key = get_str("api_key", t);
-                    let running_api = if api_key.is_some() {
-                        let api_cert = get_str_attr("api_cert", t);
-                        let api_authorized_cert = get_str_attr("api_authorized_cert", t);
-                        let api = Api::new(&api_key.unwrap(), &api_cert, &api_authorized_cert, main_channel);
-            	        let running_api = api.spawn();
-            	        sni_map.insert("tnexus.net".to_string(), format!("127.0.0.1:{}", running_api.local_addr.port()));
-            	        Some(running_api)
-                    } else {
-                        None
-                    };
-
-        		    debug!("[{}] Forwarding {} to {:?}", name, endpoint, sni_map);
-
-        		    Endpoint {
-                        bufsize: bufsize,
-                        listen: endpoint,
-                        destination: Box::new(SniPlexer::new(&default, sni_map)),
-                        api: running_api,
-        		    }
-		        },
-		        _ => {
-        		    let destination = get_str_attr("destination", t);
-        		    debug!("[{}] Forwarding {} to {}", name, endpoint, destination);
-        
-        		    Endpoint {
-                        bufsize: bufsize,
-                        listen: endpoint,
-                        destination: Box::new(FixedPlexer::new(&destination)),
-                        api: None,
-        		    }
-		        }
-		    }
+    if listens.len() > MAX_LISTENERS {
+        errors.push(ConfigError::invalid("listen", format!("too many [[listen]] sections, the max is {}", MAX_LISTENERS)));
+    }
+
+    let mut endpoints: Vec<Endpoint> = Vec::new();
+
+    for (i, listen) in listens.iter().enumerate() {
+        let prefix = format!("listen[{}]", i);
+        let endpoint = match parse_endpoint(defaults, listen, &prefix, errors) {
+            Some(endpoint) => endpoint,
+            None => continue,
+        };
+
+        if endpoints.iter().any(|e| e.name == endpoint.name) {
+            errors.push(ConfigError::invalid(&key(&prefix, "name"), format!("duplicate name {}", endpoint.name)));
+        }
+        if let Some(other) = endpoints.iter().find(|e| e.listen == endpoint.listen) {
+            errors.push(ConfigError::invalid(&key(&prefix, "endpoint"),
+                                             format!("{} is already used by [{}]", endpoint.listen, other.name)));
+        }
+        endpoints.push(endpoint);
+    }
+
+    endpoints
+}
+
+fn parse_endpoint(defaults: &Defaults, listen: &Value, prefix: &str, errors: &mut Vec<ConfigError>) -> Option<Endpoint> {
+    let t = match *listen {
+		Value::Table(ref t) => t,
+		_ => {
+		    errors.push(ConfigError::invalid(prefix, "listen section should be an array of tables"));
+		    return None;
 		},
-		_ => panic!("Invalid configration file: listen section should be an array of tables"),        
+    };
+
+    let count = errors.len();
+
+    let name = check(errors, get_str_attr("name", t, prefix));
+    let endpoint = check(errors, get_str_attr("endpoint", t, prefix).and_then(|endpoint| {
+        try!(validate_endpoint(&endpoint).map_err(|e| ConfigError::invalid(&key(prefix, "endpoint"), e)));
+        Ok(endpoint)
+    }));
+    let bufsize = check(errors, get_positive_int("bufsize", Some(t), prefix)).unwrap_or(None).unwrap_or(defaults.bufsize);
+
+    let api_key = check(errors, get_str("api_key", t, prefix)).unwrap_or(None);
+    let api = match api_key {
+        Some(api_key) => {
+            let cert = check(errors, get_str_attr("api_cert", t, prefix));
+            let authorized_cert = check(errors, get_str_attr("api_authorized_cert", t, prefix));
+            match (cert, authorized_cert) {
+                (Some(cert), Some(authorized_cert)) => {
+                    // so that starting the API cannot fail on them later
+                    check(errors, load_tls(&api_key, &cert, &authorized_cert)
+                          .map_err(|e| ConfigError::invalid(&key(prefix, "api_key"), e)));
+                    Some(ApiConfig {
+                        key: api_key,
+                        cert: cert,
+                        authorized_cert: authorized_cert,
+                    })
+                },
+                _ => None,
+            }
+        },
+        None => None,
+    };
+
+    let health_check = parse_health_check(t, prefix, errors);
+    let accept_proxy = check(errors, get_bool("accept_proxy_protocol", t, prefix)).unwrap_or(None).unwrap_or(false);
+    let trusted_proxies = check(errors, get_cidrs("trusted_proxies", t, prefix));
+    // clients of a Unix domain socket have no address to check
+    let unix = endpoint.as_ref().map_or(false, |endpoint| endpoint.starts_with(UNIX_PREFIX));
+    if unix && t.contains_key("trusted_proxies") {
+        errors.push(ConfigError::invalid(&key(prefix, "trusted_proxies"), "cannot be checked for a Unix domain socket"));
+    } else if !accept_proxy && t.contains_key("trusted_proxies") {
+        errors.push(ConfigError::invalid(&key(prefix, "trusted_proxies"), "requires accept_proxy_protocol"));
+    } else if !unix && accept_proxy && trusted_proxies.as_ref().map_or(false, |proxies| proxies.is_empty()) {
+        // or anyone could claim to be anyone
+        errors.push(ConfigError::invalid(&key(prefix, "accept_proxy_protocol"), "requires trusted_proxies"));
+    }
+    let acl = check(errors, get_acl(t, prefix)).unwrap_or(Acl::default());
+
+	if !t.contains_key("multiplex") {
+	    if api.is_some() {
+	        // the API is reached thru SNI
+	        errors.push(ConfigError::invalid(&key(prefix, "api_key"), "the API requires SNI multiplexing"));
+	    }
+	    for attr in ["fingerprints", "blocked_fingerprints"].iter() {
+	        if t.contains_key(*attr) {
+	            // fingerprints come from the ClientHello
+	            errors.push(ConfigError::invalid(&key(prefix, attr), "requires SNI multiplexing"));
+	        }
+	    }
+	}
+
+	let routing = match t.get("protocols") {
+	    Some(&Value::Table(ref pt)) => parse_protocol_routing(defaults, t, pt, prefix, bufsize, errors),
+	    Some(_) => {
+	        errors.push(ConfigError::invalid(&key(prefix, "protocols"), "should be a table"));
+	        None
+	    },
+	    None => {
+	        for attr in ["protocol_missing", "wait_timeout"].iter() {
+	            if t.contains_key(*attr) {
+	                errors.push(ConfigError::invalid(&key(prefix, attr), "requires protocols"));
+	            }
+	        }
+
+	        match (t.get("multiplex"), t.get("http_multiplex")) {
+	            (Some(_), Some(_)) => {
+	                // unless the protocol tells them apart
+	                errors.push(ConfigError::invalid(&key(prefix, "http_multiplex"), "cannot be combined with multiplex (without protocols)"));
+	                None
+	            },
+	            (Some(mv), None) => parse_sni_routing(defaults, t, mv, prefix, bufsize, errors),
+	            (None, Some(hv)) => parse_http_routing(defaults, t, hv, prefix, bufsize, errors),
+	            (None, None) => check(errors, get_destinations_attr("destination", t, prefix)).map(Routing::Fixed),
+	        }
+	    },
+	};
+
+    match (name, endpoint, routing) {
+        (Some(name), Some(endpoint), Some(routing)) if errors.len() == count => {
+		    debug!("[{}] Forwarding {} to {:?}", name, endpoint, routing);
+
+		    Some(Endpoint {
+		        name: name,
+		        bufsize: bufsize,
+		        listen: endpoint,
+		        routing: routing,
+		        api: api,
+		        health_check: health_check,
+		        accept_proxy: accept_proxy,
+		        trusted_proxies: trusted_proxies.unwrap_or(Vec::new()),
+		        acl: acl,
+		    })
+        },
+        _ => None,
+    }
+}
+
+/// Routing by SNI, for the multiplex table of a [[listen]] section.
+fn parse_sni_routing(defaults: &Defaults, t: &Table, multiplex: &Value, prefix: &str, bufsize: usize,
+                     errors: &mut Vec<ConfigError>) -> Option<Routing> {
+    let mt = match *multiplex {
+        Value::Table(ref mt) => mt,
+        _ => {
+            errors.push(ConfigError::invalid(&key(prefix, "multiplex"), "should be a table"));
+            return None;
+        },
+    };
+
+    let default = check(errors, get_destinations_attr("sni_missing", t, prefix));
+    let mismatch = check(errors, get_destinations("sni_mismatch", t, prefix)).unwrap_or(None);
+    let sni_map = get_routes(mt, &key(prefix, "multiplex"), |name| validate_route(name).map(|_| ()), errors);
+    let max_hello_size = check(errors, get_positive_int("max_hello_size", Some(t), prefix)).unwrap_or(None)
+        .unwrap_or(defaults.max_hello_size);
+
+    if max_hello_size > bufsize {
+        // the whole ClientHello has to fit in the buffer
+        errors.push(ConfigError::invalid(&key(prefix, "max_hello_size"),
+                                         format!("{} should not exceed bufsize ({})", max_hello_size, bufsize)));
+    }
+
+    let fingerprints = get_fingerprint_rules(t, prefix, errors);
+
+    default.map(|default| Routing::Sni {
+        default: default,
+        mismatch: mismatch,
+        map: sni_map,
+        max_hello_size: max_hello_size,
+        fingerprints: fingerprints,
+    })
+}
+
+/// Routing by the Host header, for the http_multiplex table of a [[listen]] section.
+fn parse_http_routing(defaults: &Defaults, t: &Table, http_multiplex: &Value, prefix: &str, bufsize: usize,
+                      errors: &mut Vec<ConfigError>) -> Option<Routing> {
+    let ht = match *http_multiplex {
+        Value::Table(ref ht) => ht,
+        _ => {
+            errors.push(ConfigError::invalid(&key(prefix, "http_multiplex"), "should be a table"));
+            return None;
+        },
+    };
+
+    let default = check(errors, get_destinations_attr("http_missing", t, prefix));
+    let host_map = get_routes(ht, &key(prefix, "http_multiplex"), validate_host_route, errors);
+    let max_header_size = check(errors, get_positive_int("max_header_size", Some(t), prefix)).unwrap_or(None)
+        .unwrap_or(defaults.max_header_size);
+
+    if max_header_size > bufsize {
+        // the whole head of the request has to fit in the buffer
+        errors.push(ConfigError::invalid(&key(prefix, "max_header_size"),
+                                         format!("{} should not exceed bufsize ({})", max_header_size, bufsize)));
+    }
+
+    default.map(|default| Routing::Http {
+        default: default,
+        map: host_map,
+        max_header_size: max_header_size,
+    })
+}
+
+/// Routing by protocol, for the protocols table of a [[listen]] section.
+///
+/// TLS goes thru SNI multiplexing if there is a multiplex table, and HTTP thru
+/// Host header multiplexing if there is an http_multiplex table.
+fn parse_protocol_routing(defaults: &Defaults, t: &Table, pt: &Table, prefix: &str, bufsize: usize,
+                          errors: &mut Vec<ConfigError>) -> Option<Routing> {
+    let mut protocols = BTreeMap::new();
+
+    for (protocol, value) in pt.iter() {
+        let key = key(&key(prefix, "protocols"), protocol);
+        if !PROTOCOLS.contains(&&protocol[..]) {
+            errors.push(ConfigError::invalid(&key, format!("unknown protocol, expected one of {}", PROTOCOLS.join(", "))));
+            continue;
+        }
+        if let Some(destinations) = check(errors, to_destinations(value, &key)) {
+            protocols.insert(protocol.clone(), Routing::Fixed(destinations));
+        }
+    }
+
+    for &(protocol, attr) in [("tls", "multiplex"), ("http", "http_multiplex")].iter() {
+        let value = match t.get(attr) {
+            Some(value) => value,
+            None => continue,
+        };
+        if pt.contains_key(protocol) {
+            errors.push(ConfigError::invalid(&key(&key(prefix, "protocols"), protocol), format!("conflicts with {}", attr)));
+        }
+        let routing = match protocol {
+            "tls" => parse_sni_routing(defaults, t, value, prefix, bufsize, errors),
+            _ => parse_http_routing(defaults, t, value, prefix, bufsize, errors),
+        };
+        if let Some(routing) = routing {
+            protocols.insert(protocol.to_string(), routing);
+        }
     }
+
+    let default = check(errors, get_destinations("protocol_missing", t, prefix)).unwrap_or(None);
+    let wait_timeout = check(errors, get_positive_int("wait_timeout", Some(t), prefix)).unwrap_or(None)
+        .map(|ms| ms as u64)
+        .unwrap_or(WAIT_TIMEOUT);
+
+    Some(Routing::Protocol {
+        protocols: protocols,
+        default: default,
+        wait_timeout: wait_timeout,
+    })
 }
 
-fn get_str_attr(attr: &str, table: &Table) -> String {
-    get_str(attr, table).expect(
-        &format!("Invalid configration file: No String({}) attribute in {:?}", attr, table))
+/// The active health checks of a [[listen]] section, if it has health_check = "tcp" or "tls".
+fn parse_health_check(t: &Table, prefix: &str, errors: &mut Vec<ConfigError>) -> Option<HealthCheck> {
+    let kind = check(errors, get_str("health_check", t, prefix)).unwrap_or(None);
+    let server_name = check(errors, get_str("health_check_server_name", t, prefix)).unwrap_or(None);
+    let interval = check(errors, get_positive_int("health_check_interval", Some(t), prefix)).unwrap_or(None);
+    let timeout = check(errors, get_positive_int("health_check_timeout", Some(t), prefix)).unwrap_or(None);
+
+    let probe = match kind {
+        None => {
+            for attr in ["health_check_server_name", "health_check_interval", "health_check_timeout"].iter() {
+                if t.contains_key(*attr) {
+                    errors.push(ConfigError::invalid(&key(prefix, attr), "requires health_check"));
+                }
+            }
+            return None;
+        },
+        Some(ref kind) if kind == "tcp" => {
+            if server_name.is_some() {
+                errors.push(ConfigError::invalid(&key(prefix, "health_check_server_name"), "requires health_check = \"tls\""));
+            }
+            Check::Tcp
+        },
+        Some(ref kind) if kind == "tls" => Check::Tls(server_name),
+        Some(kind) => {
+            errors.push(ConfigError::invalid(&key(prefix, "health_check"),
+                                             format!("should be \"tcp\" or \"tls\", not \"{}\"", kind)));
+            return None;
+        },
+    };
+
+    Some(HealthCheck {
+        check: probe,
+        interval: interval.map(|ms| ms as u64).unwrap_or(CHECK_INTERVAL),
+        timeout: timeout.map(|ms| ms as u64).unwrap_or(CHECK_TIMEOUT),
+    })
 }
 
-fn get_str(attr: &str, table: &Table) -> Option<String> {
+fn key(prefix: &str, attr: &str) -> String {
+    format!("{}.{}", prefix, attr)
+}
+
+fn get_str_attr(attr: &str, table: &Table, prefix: &str) -> Result<String, ConfigError> {
+    try!(get_str(attr, table, prefix)).ok_or(ConfigError::invalid(&key(prefix, attr), "missing"))
+}
+
+fn get_str(attr: &str, table: &Table, prefix: &str) -> Result<Option<String>, ConfigError> {
     match table.get(attr) {
-        Some(&Value::String(ref result)) => Some(result.clone()),
-        _ => None,
+        Some(&Value::String(ref result)) => Ok(Some(result.clone())),
+        Some(v) => Err(ConfigError::invalid(&key(prefix, attr), format!("should be a string, not {}", v))),
+        None => Ok(None),
     }
 }
 
-fn get_sni_map(multiplex: &BTreeMap<String, Value>) -> BTreeMap<String, String> {
+fn get_bool(attr: &str, table: &Table, prefix: &str) -> Result<Option<bool>, ConfigError> {
+    match table.get(attr) {
+        Some(&Value::Boolean(result)) => Ok(Some(result)),
+        Some(v) => Err(ConfigError::invalid(&key(prefix, attr), format!("should be true or false, not {}", v))),
+        None => Ok(None),
+    }
+}
+
+fn get_positive_int(attr: &str, table: Option<&Table>, prefix: &str) -> Result<Option<usize>, ConfigError> {
+    match table.and_then(|t| t.get(attr)) {
+        Some(&Value::Integer(ref i)) if *i > 0 => Ok(Some((*i) as usize)),
+        Some(v) => Err(ConfigError::invalid(&key(prefix, attr), format!("should be a positive integer, not {}", v))),
+        None => Ok(None),
+    }
+}
+
+/// Checks that a listening endpoint is a literal "ip:port" address or a "unix:/path".
+fn validate_endpoint(endpoint: &str) -> Result<(), String> {
+    Addr::parse(endpoint)
+        .map(|_| ())
+        .ok_or(format!("Bad endpoint {}, expected \"ip:port\" or \"unix:/path\"", endpoint))
+}
+
+/// Checks that a destination is an "ip:port" address, a "host:port" to resolve thru DNS or a "unix:/path".
+pub fn validate_destination(destination: &str) -> Result<(), String> {
+    Backend::parse(destination).map(|_| ())
+}
+
+/// Reads a destination attribute, which is either a single "ip:port" (or "host:port" or "unix:/path") string, an array of them
+/// (alternates to try in order if connecting fails), or a pool of backends.
+fn get_destinations(attr: &str, table: &Table, prefix: &str) -> Result<Option<Destinations>, ConfigError> {
+    match table.get(attr) {
+        Some(value) => to_destinations(value, &key(prefix, attr)).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn get_destinations_attr(attr: &str, table: &Table, prefix: &str) -> Result<Destinations, ConfigError> {
+    try!(get_destinations(attr, table, prefix)).ok_or(ConfigError::invalid(&key(prefix, attr), "missing"))
+}
+
+/// A pool is a table like { backends = ["ip:port", ...], strategy = "weighted", weights = [3, 1] },
+/// the strategy defaults to round-robin. send_proxy_protocol = "v1" or "v2" sends a PROXY protocol
+/// header to the backends, allow and deny restrict the clients taking the route (see get_acl).
+fn to_destinations(value: &Value, key: &str) -> Result<Destinations, ConfigError> {
+    let pool = match *value {
+        Value::Table(ref pool) => pool,
+        _ => return to_backends(value, key).map(Destinations::new),
+    };
+
+    let backends_key = format!("{}.backends", key);
+    let backends = match pool.get("backends") {
+        Some(value) => try!(to_backends(value, &backends_key)),
+        None => return Err(ConfigError::invalid(&backends_key, "missing")),
+    };
+
+    let strategy = match try!(get_str("strategy", pool, key)) {
+        Some(name) => try!(Strategy::parse(&name).map_err(|e| ConfigError::invalid(&format!("{}.strategy", key), e))),
+        None => Strategy::RoundRobin,
+    };
+
+    let weights_key = format!("{}.weights", key);
+    let mut weights = Vec::new();
+    match pool.get("weights") {
+        Some(&Value::Array(ref vals)) => {
+            for val in vals.iter() {
+                match *val {
+                    Value::Integer(weight) if weight > 0 => weights.push(weight as usize),
+                    _ => return Err(ConfigError::invalid(&weights_key, format!("Bad weight {}, expected a positive integer", val))),
+                }
+            }
+        },
+        Some(_) => return Err(ConfigError::invalid(&weights_key, "should be an array of positive integers")),
+        None => (),
+    }
+
+    let send_proxy = match try!(get_str("send_proxy_protocol", pool, key)) {
+        Some(name) => Some(try!(proxy::Version::parse(&name)
+            .map_err(|e| ConfigError::invalid(&format!("{}.send_proxy_protocol", key), e)))),
+        None => None,
+    };
+
+    let acl = try!(get_acl(pool, key));
+
+    Destinations::pool(backends, strategy, weights, send_proxy, acl).map_err(|e| ConfigError::invalid(key, e))
+}
+
+/// Reads the allow and deny lists of address ranges, like allow = ["10.0.0.0/8", "2001:db8::/32"].
+fn get_acl(table: &Table, prefix: &str) -> Result<Acl, ConfigError> {
+    Ok(Acl {
+        allow: try!(get_cidrs("allow", table, prefix)),
+        deny: try!(get_cidrs("deny", table, prefix)),
+    })
+}
+
+fn get_cidrs(attr: &str, table: &Table, prefix: &str) -> Result<Vec<Cidr>, ConfigError> {
+    let key = key(prefix, attr);
+    let mut result = Vec::new();
+    match table.get(attr) {
+        Some(&Value::Array(ref values)) => {
+            for value in values.iter() {
+                match *value {
+                    Value::String(ref cidr) => result.push(try!(Cidr::parse(cidr).map_err(|e| ConfigError::invalid(&key, e)))),
+                    _ => return Err(ConfigError::invalid(&key, format!("Bad address range {}", value))),
+                }
+            }
+        },
+        Some(_) => return Err(ConfigError::invalid(&key, "should be an array of strings")),
+        None => (),
+    }
+    Ok(result)
+}
+
+/// Reads "ip:port" (or "host:port" or "unix:/path") or an array of them.
+fn to_backends(value: &Value, key: &str) -> Result<Vec<String>, ConfigError> {
+    let result = match value {
+        &Value::String(ref val) => vec![val.clone()],
+        &Value::Array(ref vals) if !vals.is_empty() => {
+            let mut result = Vec::new();
+            for val in vals.iter() {
+                match val {
+                    &Value::String(ref val) => result.push(val.clone()),
+                    _ => return Err(ConfigError::invalid(key, format!("Bad destination {}", val))),
+                }
+            }
+            result
+        },
+        _ => return Err(ConfigError::invalid(key, format!("Bad destination {}, expected \"ip:port\", \"host:port\" or \"unix:/path\", an array of them or a pool", value))),
+    };
+
+    for destination in result.iter() {
+        try!(validate_destination(destination).map_err(|e| ConfigError::invalid(key, e)));
+    }
+
+    Ok(result)
+}
+
+/// Reads the routes by client fingerprint (`fingerprints`) and the fingerprints to drop (`blocked_fingerprints`).
+fn get_fingerprint_rules(table: &Table, prefix: &str, errors: &mut Vec<ConfigError>) -> fingerprint::Rules<Destinations> {
+    let mut rules = fingerprint::Rules::default();
+
+    match table.get("fingerprints") {
+        Some(&Value::Table(ref routes)) => {
+            for (fingerprint, value) in routes.iter() {
+                let key = format!("{}.\"{}\"", key(prefix, "fingerprints"), fingerprint);
+                check(errors, fingerprint::validate(fingerprint).map_err(|e| ConfigError::invalid(&key, e)));
+                if let Some(destinations) = check(errors, to_destinations(value, &key)) {
+                    rules.routes.insert(fingerprint.clone(), destinations);
+                }
+            }
+        },
+        Some(_) => errors.push(ConfigError::invalid(&key(prefix, "fingerprints"), "should be a table")),
+        None => (),
+    }
+
+    let blocked_key = key(prefix, "blocked_fingerprints");
+    match table.get("blocked_fingerprints") {
+        Some(&Value::Array(ref blocked)) => {
+            for value in blocked.iter() {
+                match *value {
+                    Value::String(ref fingerprint) => {
+                        if check(errors, fingerprint::validate(fingerprint).map_err(|e| ConfigError::invalid(&blocked_key, e))).is_some() {
+                            rules.blocked.push(fingerprint.clone());
+                        }
+                    },
+                    _ => errors.push(ConfigError::invalid(&blocked_key, format!("Bad fingerprint {}", value))),
+                }
+            }
+        },
+        Some(_) => errors.push(ConfigError::invalid(&blocked_key, "should be an array of strings")),
+        None => (),
+    }
+
+    rules
+}
+
+/// Reads a table of routes by host name, checking the names with `validate`.
+fn get_routes<F>(routes: &Table, prefix: &str, validate: F, errors: &mut Vec<ConfigError>) -> BTreeMap<String, Destinations>
+        where F: Fn(&str) -> Result<(), String> {
     let mut result = BTreeMap::new();
     
-    for (key, value) in multiplex.iter() {
-        match value {
-            &Value::String(ref val) => {
-                result.insert(key.clone(), val.clone());
-            },
-            _ => panic!("Invalid configration file: Bad SNI map: {:?}", multiplex),
+    for (sni, value) in routes.iter() {
+        let key = format!("{}.\"{}\"", prefix, sni);
+        check(errors, validate(sni).map_err(|e| ConfigError::invalid(&key, e)));
+        if let Some(destinations) = check(errors, to_destinations(value, &key)) {
+            result.insert(sni.clone(), destinations);
         }
     }
     
@@ -0,0 +1,169 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+use mio::{NotifyError, Sender, Token};
+
+use api::ApiMsg;
+
+/// Default for how long the addresses of a host name are cached, in ms.
+pub const DNS_TTL: u64 = 60000;
+
+// how long a failed lookup is cached, in ms (or the previous addresses are kept)
+const ERROR_TTL: u64 = 5000;
+
+/// Outcome of looking up a host name in the cache.
+#[derive(Debug)]
+pub enum Lookup {
+    Found(Vec<IpAddr>),
+    /// Being resolved, see `Resolver::wait`.
+    Pending,
+    Failed(String),
+}
+
+/// Resolves host names off the event loop, caching their addresses, one per worker.
+///
+/// getaddrinfo(3) blocks, so every lookup runs on a thread of its own, which hands the
+/// outcome back to the event loop as ApiMsg::Resolved (see `resolved`).
+/// Expired addresses are still used while they are refreshed.
+#[derive(Clone)]
+pub struct Resolver(Rc<RefCell<Cache>>);
+
+struct Cache {
+    entries: HashMap<String, Entry>,
+
+    // host names being resolved, with the flows waiting for them
+    waiting: HashMap<String, Vec<Token>>,
+
+    // where lookups report back to
+    notify: Sender<ApiMsg>,
+
+    // in ms
+    ttl: u64,
+}
+
+struct Entry {
+    addrs: Result<Vec<IpAddr>, String>,
+    expires: Instant,
+}
+
+impl Resolver {
+    pub fn new(notify: Sender<ApiMsg>, ttl: u64) -> Resolver {
+        Resolver(Rc::new(RefCell::new(Cache {
+            entries: HashMap::new(),
+            waiting: HashMap::new(),
+            notify: notify,
+            ttl: ttl,
+        })))
+    }
+
+    /// Changes the TTL (of the next lookups).
+    pub fn set_ttl(&self, ttl: u64) {
+        self.0.borrow_mut().ttl = ttl;
+    }
+
+    /// The addresses of the host name, starting to resolve it if they are not cached
+    /// or expired (expired addresses are still returned).
+    pub fn lookup(&self, host: &str) -> Lookup {
+        let mut cache = self.0.borrow_mut();
+        let now = Instant::now();
+
+        let (lookup, expired) = match cache.entries.get(host) {
+            Some(entry) => {
+                let expired = entry.expires <= now;
+                match entry.addrs {
+                    Ok(ref addrs) => (Lookup::Found(addrs.clone()), expired),
+                    // try again, rather than failing right away
+                    Err(_) if expired => (Lookup::Pending, true),
+                    Err(ref e) => (Lookup::Failed(e.clone()), false),
+                }
+            },
+            None => (Lookup::Pending, true),
+        };
+
+        if expired && !cache.waiting.contains_key(host) {
+            if let Err(e) = spawn_lookup(host.to_string(), cache.notify.clone()) {
+                error!("Failed to start resolver thread, {:?}", e);
+                if let Lookup::Pending = lookup {
+                    return Lookup::Failed(format!("{}", e));
+                }
+                return lookup;
+            }
+            cache.waiting.insert(host.to_string(), Vec::new());
+        }
+
+        lookup
+    }
+
+    /// Has the flow resumed (see `resolved`) once the host name being resolved is.
+    pub fn wait(&self, host: &str, token: Token) {
+        if let Some(tokens) = self.0.borrow_mut().waiting.get_mut(host) {
+            tokens.push(token);
+        }
+    }
+
+    /// Caches what a lookup came up with, and returns the flows waiting for it.
+    pub fn resolved(&self, host: String, result: Result<Vec<IpAddr>, String>) -> Vec<Token> {
+        let mut cache = self.0.borrow_mut();
+        let now = Instant::now();
+
+        let entry = match result {
+            Ok(ref addrs) if !addrs.is_empty() => {
+                debug!("Resolved {} to {:?}", host, addrs);
+                Entry {
+                    addrs: Ok(addrs.clone()),
+                    expires: now + Duration::from_millis(cache.ttl),
+                }
+            },
+            _ => {
+                let e = result.err().unwrap_or("no addresses".to_string());
+                let expires = now + Duration::from_millis(ERROR_TTL);
+                match cache.entries.get(&host) {
+                    Some(&Entry { addrs: Ok(ref addrs), .. }) => {
+                        warn!("Failed to resolve {}, keeping {:?} for now: {}", host, addrs, e);
+                        Entry { addrs: Ok(addrs.clone()), expires: expires }
+                    },
+                    _ => {
+                        warn!("Failed to resolve {}: {}", host, e);
+                        Entry { addrs: Err(e), expires: expires }
+                    },
+                }
+            },
+        };
+        cache.entries.insert(host.clone(), entry);
+
+        cache.waiting.remove(&host).unwrap_or(Vec::new())
+    }
+}
+
+fn spawn_lookup(host: String, notify: Sender<ApiMsg>) -> io::Result<()> {
+    debug!("Resolving {}", host);
+    thread::Builder::new().name("resolver".to_string()).spawn(move || {
+        let result = (&host[..], 0).to_socket_addrs()
+            .map(|addrs| {
+                let mut ips: Vec<IpAddr> = Vec::new();
+                for addr in addrs {
+                    if !ips.contains(&addr.ip()) {
+                        ips.push(addr.ip());
+                    }
+                }
+                ips
+            })
+            .map_err(|e| format!("{}", e));
+
+        // the flows waiting for it would wait forever, so keep trying unless the worker is gone
+        let mut msg = ApiMsg::Resolved(host, result);
+        loop {
+            match notify.send(msg) {
+                Err(NotifyError::Full(m)) => {
+                    msg = m;
+                    thread::sleep(Duration::from_millis(10));
+                },
+                _ => break,
+            }
+        }
+    }).map(|_| ())
+}
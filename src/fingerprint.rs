@@ -0,0 +1,266 @@
+use std::cmp;
+use std::collections::BTreeMap;
+
+use openssl::crypto::hash::{hash, Type};
+use rustc_serialize::hex::ToHex;
+
+use tls::{ClientHello, EXTENSION_SERVER_NAME, EXTENSION_ALPN};
+
+/// Fingerprints of a TLS client, derived from its ClientHello.
+///
+/// See https://github.com/salesforce/ja3 and https://github.com/FoxIO-LLC/ja4
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct Fingerprint {
+    // MD5 of the JA3 string, e.g. e7d705a3286e19ea42f587b344ee6865
+    pub ja3: String,
+    // e.g. t13d1516h2_8daaf6152771_e5627efa2ab1
+    pub ja4: String,
+}
+
+impl Fingerprint {
+    pub fn new(hello: &ClientHello) -> Fingerprint {
+        Fingerprint {
+            ja3: hash(Type::MD5, ja3_string(hello).as_bytes()).to_hex(),
+            ja4: ja4(hello),
+        }
+    }
+
+    /// Whether this is the given JA3 or JA4 fingerprint.
+    pub fn is(&self, fingerprint: &str) -> bool {
+        self.ja3 == fingerprint || self.ja4 == fingerprint
+    }
+}
+
+/// Routes (or blocks) TLS clients by fingerprint, regardless of the server name they ask for.
+#[derive(Clone, Debug)]
+pub struct Rules<D> {
+    // destinations by JA3 or JA4 fingerprint
+    pub routes: BTreeMap<String, D>,
+    // connections with these fingerprints are dropped
+    pub blocked: Vec<String>,
+}
+
+impl<D> Default for Rules<D> {
+    fn default() -> Rules<D> {
+        Rules {
+            routes: BTreeMap::new(),
+            blocked: Vec::new(),
+        }
+    }
+}
+
+impl<D> Rules<D> {
+    pub fn blocks(&self, fingerprint: &Fingerprint) -> bool {
+        self.blocked.iter().any(|blocked| fingerprint.is(blocked))
+    }
+
+    /// The destinations of a fingerprint, a JA4 route beats a JA3 one.
+    pub fn route(&self, fingerprint: &Fingerprint) -> Option<&D> {
+        self.routes.get(&fingerprint.ja4).or_else(|| self.routes.get(&fingerprint.ja3))
+    }
+
+    /// The same rules, with the destinations converted by `f`.
+    pub fn map_routes<E, F>(&self, f: F) -> Rules<E> where F: Fn(&D) -> E {
+        Rules {
+            routes: self.routes.iter().map(|(fingerprint, destinations)| (fingerprint.clone(), f(destinations))).collect(),
+            blocked: self.blocked.clone(),
+        }
+    }
+}
+
+/// Checks that a fingerprint looks like a JA3 hash or a JA4 fingerprint.
+pub fn validate(fingerprint: &str) -> Result<(), String> {
+    let parts: Vec<&str> = fingerprint.split('_').collect();
+    let valid = match parts.len() {
+        1 => is_hex(fingerprint, 32),
+        3 => parts[0].len() == 10 && is_hex(parts[1], 12) && is_hex(parts[2], 12),
+        _ => false,
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Bad fingerprint {}, expected a JA3 hash or a JA4 fingerprint (in lowercase)", fingerprint))
+    }
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.chars().all(|c| c.is_digit(16) && !c.is_uppercase())
+}
+
+/// GREASE values (RFC 8701) are random noise, not a property of the client.
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn without_grease(values: &[u16]) -> Vec<u16> {
+    values.iter().cloned().filter(|value| !is_grease(*value)).collect()
+}
+
+fn join<T, F>(values: &[T], separator: &str, format: F) -> String where F: Fn(&T) -> String {
+    values.iter().map(format).collect::<Vec<String>>().join(separator)
+}
+
+/// SSLVersion,Ciphers,Extensions,EllipticCurves,EllipticCurvePointFormats
+fn ja3_string(hello: &ClientHello) -> String {
+    let extensions: Vec<u16> = hello.extensions.iter().map(|extension| extension.kind).collect();
+
+    format!("{},{},{},{},{}",
+            hello.legacy_version,
+            join(&without_grease(&hello.cipher_suites), "-", |v| v.to_string()),
+            join(&without_grease(&extensions), "-", |v| v.to_string()),
+            join(&without_grease(&hello.supported_groups), "-", |v| v.to_string()),
+            join(&hello.ec_point_formats, "-", |v| v.to_string()))
+}
+
+/// JA4 for TLS over TCP: <protocol><version><sni><#ciphers><#extensions><alpn>_<ciphers>_<extensions>
+fn ja4(hello: &ClientHello) -> String {
+    let mut ciphers = without_grease(&hello.cipher_suites);
+    let extensions: Vec<u16> = hello.extensions.iter().map(|extension| extension.kind).collect();
+    let mut extensions = without_grease(&extensions);
+
+    let version = without_grease(&hello.supported_versions).into_iter().max().unwrap_or(hello.legacy_version);
+    let sni = if extensions.contains(&EXTENSION_SERVER_NAME) { "d" } else { "i" };
+
+    let a = format!("t{}{}{:02}{:02}{}", ja4_version(version), sni,
+                    cmp::min(ciphers.len(), 99), cmp::min(extensions.len(), 99),
+                    ja4_alpn(hello.alpn.first()));
+
+    ciphers.sort();
+    let b = join(&ciphers, ",", |v| format!("{:04x}", v));
+
+    // the server name and the protocol are already part of a
+    extensions.retain(|kind| *kind != EXTENSION_SERVER_NAME && *kind != EXTENSION_ALPN);
+    extensions.sort();
+    let mut c = join(&extensions, ",", |v| format!("{:04x}", v));
+    let signature_algorithms = without_grease(&hello.signature_algorithms);
+    if !signature_algorithms.is_empty() {
+        c = format!("{}_{}", c, join(&signature_algorithms, ",", |v| format!("{:04x}", v)));
+    }
+
+    format!("{}_{}_{}", a, ja4_hash(&b), ja4_hash(&c))
+}
+
+fn ja4_version(version: u16) -> &'static str {
+    match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        0x0002 => "s2",
+        0xfeff => "d1",
+        0xfefd => "d2",
+        0xfefc => "d3",
+        _ => "00",
+    }
+}
+
+/// The first and last characters of the first ALPN protocol (or of its hex, if they are not alphanumeric).
+fn ja4_alpn(protocol: Option<&String>) -> String {
+    let bytes = match protocol {
+        Some(protocol) if !protocol.is_empty() => protocol.as_bytes(),
+        _ => return "00".to_string(),
+    };
+
+    let (first, last) = (bytes[0], bytes[bytes.len() - 1]);
+    if is_alphanumeric(first) && is_alphanumeric(last) {
+        format!("{}{}", first as char, last as char)
+    } else {
+        let hex = bytes.to_hex();
+        format!("{}{}", &hex[..1], &hex[hex.len() - 1..])
+    }
+}
+
+fn is_alphanumeric(byte: u8) -> bool {
+    byte < 128 && (byte as char).is_alphanumeric()
+}
+
+/// The first 12 hex digits of the SHA256 of a list, or zeroes for an empty one.
+fn ja4_hash(list: &str) -> String {
+    if list.is_empty() {
+        "000000000000".to_string()
+    } else {
+        hash(Type::SHA256, list.as_bytes()).to_hex()[..12].to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tls::{parse_client_hello, ClientHello, Extension, ParseResult, MAX_HELLO_SIZE};
+
+    // of curl-7.88-no-sni.bin, worked out from the JA3 and JA4 specs (not with this code)
+    const CURL_JA3_STRING: &'static str = "771,4866-4867-4865-49196-49200-159-52393-52392-52394-49195-49199-158-49188-\
+                                           49192-107-49187-49191-103-49162-49172-57-49161-49171-51-157-156-61-60-53-47-\
+                                           255,11-10-16-22-23-49-13-43-45-51-21,29-23-30-25-24-256-257-258-259-260,0-1-2";
+    const CURL_JA3: &'static str = "78f0dc5ac5b19daf131a133cfdee9691";
+    const CURL_JA4: &'static str = "t13i3111h2_e8f1e7e78f70_b26ce05bbdd6";
+
+    fn curl_hello() -> ClientHello {
+        match parse_client_hello(include_bytes!("../testdata/client_hello/curl-7.88-no-sni.bin"), MAX_HELLO_SIZE) {
+            ParseResult::Hello(hello) => hello,
+            result => panic!("expected a hello, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn curl() {
+        let hello = curl_hello();
+        assert_eq!(ja3_string(&hello), CURL_JA3_STRING);
+
+        let fingerprint = Fingerprint::new(&hello);
+        assert_eq!(fingerprint.ja3, CURL_JA3);
+        assert_eq!(fingerprint.ja4, CURL_JA4);
+        assert!(validate(&fingerprint.ja3).is_ok());
+        assert!(validate(&fingerprint.ja4).is_ok());
+    }
+
+    #[test]
+    fn ignores_grease() {
+        let mut hello = curl_hello();
+        hello.cipher_suites.insert(0, 0x0a0a);
+        hello.extensions.insert(0, Extension { kind: 0x1a1a, data: Vec::new() });
+        hello.extensions.push(Extension { kind: 0xfafa, data: vec![0] });
+        hello.supported_groups.insert(0, 0x2a2a);
+        // would be the highest version
+        hello.supported_versions.insert(0, 0xdada);
+        hello.signature_algorithms.insert(3, 0x4a4a);
+
+        let fingerprint = Fingerprint::new(&hello);
+        assert_eq!(fingerprint.ja3, CURL_JA3);
+        assert_eq!(fingerprint.ja4, CURL_JA4);
+    }
+
+    #[test]
+    fn sorts_ja4_lists() {
+        let mut hello = curl_hello();
+        hello.cipher_suites.reverse();
+        hello.extensions.reverse();
+
+        let fingerprint = Fingerprint::new(&hello);
+        assert!(fingerprint.ja3 != CURL_JA3);
+        assert_eq!(fingerprint.ja4, CURL_JA4);
+    }
+
+    #[test]
+    fn counts_but_does_not_list_sni_and_alpn() {
+        let mut hello = curl_hello();
+        hello.extensions.insert(0, Extension { kind: EXTENSION_SERVER_NAME, data: Vec::new() });
+        assert_eq!(ja4(&hello), "t13d3112h2_e8f1e7e78f70_b26ce05bbdd6");
+
+        hello.alpn.clear();
+        assert_eq!(ja4(&hello), "t13d311200_e8f1e7e78f70_b26ce05bbdd6");
+    }
+
+    #[test]
+    fn appends_signature_algorithms_in_their_order() {
+        let mut hello = curl_hello();
+        hello.signature_algorithms.clear();
+        assert_eq!(ja4(&hello), "t13i3111h2_e8f1e7e78f70_5b319e4b14d4");
+
+        let mut hello = curl_hello();
+        hello.signature_algorithms.reverse();
+        assert!(ja4(&hello) != CURL_JA4);
+    }
+}
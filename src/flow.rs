@@ -1,17 +1,27 @@
+use std::cell::Cell;
 use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
 use mio::*;
-use mio::tcp::TcpStream;
 use bytes::buf::{Buf, RingBuf};
 
+use acl::Acl;
 use multiplex::{Multiplexer, MR};
+use fingerprint::Fingerprint;
+use pool::{Backends, Pool, Resolution};
+use dns::Resolver;
+use stream::{Addr, Stream};
+use api::FlowInfo;
+use proxy;
+use tls;
 
-use Nexus;
+use {Nexus, Timer};
 
 const OUTMASK: usize = 2147483648; //2 ** 31
 
 pub struct Conn {
     // socket
-    sock: TcpStream,
+    sock: Stream,
 
     // token used to register the inbound with the event loop
     token: Token,
@@ -26,6 +36,10 @@ pub struct Conn {
     // whether the socket can accept writes right now
     writable: bool,
 
+    // whether the socket is known to be connected
+    // (outbound connections become connected asynchronously)
+    connected: bool,
+
     // octets in transit
     buf: RingBuf,
 }
@@ -37,19 +51,68 @@ pub struct Flow {
 
     // inbound connection
     pub inb: Conn,
+
+    // token of the listener that accepted the inbound connection
+    pub listener: Token,
+
+    // size for the buffer between the inbound/outbound streams
+    bufsize: usize,
     
     // outbound connection
     out: Option<Conn>,
+
+    // destination of the outbound connection
+    destination: Option<Addr>,
+
+    // alternate destinations not tried yet, in reverse order
+    // (used if connecting to the current destination fails)
+    pending: Vec<Addr>,
+
+    // address of the inbound peer (None for clients of a Unix domain socket),
+    // or of the original client if told by a PROXY protocol header
+    client: Option<SocketAddr>,
+
+    // address the client connected to (likewise)
+    endpoint: Option<SocketAddr>,
+
+    // whether the client is yet to send its PROXY protocol header
+    expect_proxy: bool,
+
+    // clients that may connect to the listener (checked here once the PROXY protocol header tells the client)
+    acl: Rc<Acl>,
+
+    // count of the listener, of connections denied by its ACL or the ACL of a route
+    denied: Rc<Cell<usize>>,
+
+    // of the TLS client (if routed by ClientHello)
+    fingerprint: Option<Fingerprint>,
+
+    // pending while the multiplexer waits for the client to speak first (see Multiplexer::wait_timeout)
+    timeout: Option<Timeout>,
+
+    // state of the destinations: this flow counts while it has a destination,
+    // and so do its connect failures
+    backends: Backends,
+
+    // the host names of destinations, with the addresses they resolve to
+    resolver: Resolver,
+
+    // the route of the flow, while waiting for host names among its backends to be resolved
+    pool: Option<Rc<Pool>>,
+
+    // PROXY protocol header to send first on each outbound connection (if the route wants one)
+    proxy_header: Option<Vec<u8>>,
 }
 
 impl Conn {
-    fn new(token: Token, sock: TcpStream, bufsize: usize) -> Conn {
+    fn new(token: Token, sock: Stream, bufsize: usize, connected: bool) -> Conn {
         Conn {
             sock: sock,
             token: token,
             interest: EventSet::all(),
             dead: false,
             writable: false,
+            connected: connected,
             buf: RingBuf::new(bufsize),
         }
     }
@@ -84,25 +147,241 @@ impl Conn {
 
 impl Flow {
 
-    pub fn new(insock: TcpStream, /*ousock: TcpStream,*/ token: Token, bufsize: usize) -> Flow {
+    pub fn new(insock: Stream, /*ousock: TcpStream,*/ token: Token, listener: Token, bufsize: usize, accept_proxy: bool,
+               acl: Rc<Acl>, denied: Rc<Cell<usize>>, backends: Backends, resolver: Resolver) -> Flow {
+        let client = insock.peer_addr();
+        let endpoint = insock.local_addr();
         Flow {
-            inb: Conn::new(token, insock, bufsize),
+            inb: Conn::new(token, insock, bufsize, true),
+            listener: listener,
+            bufsize: bufsize,
             out: None,
+            destination: None,
+            pending: Vec::new(),
+            client: client,
+            endpoint: endpoint,
+            expect_proxy: accept_proxy,
+            acl: acl,
+            denied: denied,
+            fingerprint: None,
+            timeout: None,
+            backends: backends,
+            resolver: resolver,
+            pool: None,
+            proxy_header: None,
         }
     }
 
-    pub fn set_outbound(&mut self, outbound: TcpStream, bufsize: usize, event_loop: &mut EventLoop<Nexus>) -> bool {
+    /// Connect the outbound side of the flow to the first reachable destination.
+    ///
+    /// Destinations are tried in order. The ones not tried yet are kept around in case
+    /// the connection attempt fails asynchronously (see `check_connect`).
+    pub fn connect(&mut self, mut destinations: Vec<Addr>, event_loop: &mut EventLoop<Nexus>) -> bool {
+        destinations.reverse();
+        self.pending = destinations;
+        self.connect_next(event_loop)
+    }
+
+    /// Connect to a backend of the pool, picked among the addresses of its backends.
+    ///
+    /// If host names among them are not resolved yet, the flow waits for them (see `resolved`).
+    /// Returns false if the flow should be stopped.
+    fn connect_pool(&mut self, pool: Rc<Pool>, event_loop: &mut EventLoop<Nexus>) -> bool {
+        match pool.resolve(&self.resolver) {
+            Resolution::Addrs(addrs) => {
+                let destinations = pool.pick(self.client.map(|addr| addr.ip()), &self.backends, &addrs);
+                self.connect(destinations, event_loop)
+            },
+            Resolution::Pending(host) => {
+                debug!("{:?} waiting for {} to be resolved", self.inb.token, host);
+                self.resolver.wait(&host, self.inb.token);
+                self.pool = Some(pool);
+                true
+            },
+            Resolution::Failed(e) => {
+                error!("Giving up on {:?}: failed to resolve its destination, {}", self.inb.token, e);
+                false
+            },
+        }
+    }
+
+    /// Resume connecting, now that a host name the flow was waiting for is resolved.
+    ///
+    /// Returns false if the flow should be stopped.
+    pub fn resolved(&mut self, event_loop: &mut EventLoop<Nexus>) -> bool {
+        match self.pool.take() {
+            Some(pool) => self.connect_pool(pool, event_loop),
+            // the token went to another flow, which is not waiting
+            None => true,
+        }
+    }
+
+    fn connect_next(&mut self, event_loop: &mut EventLoop<Nexus>) -> bool {
+        if let Some(out) = self.out.take() {
+            // the socket is closed when dropped, but make sure no more events are reported for our token
+            let _ = event_loop.deregister(&out.sock);
+        }
+
+        while let Some(destination) = self.pending.pop() {
+            debug!("Connecting {:?} to {}", self.inb.token, destination);
+            self.set_destination(destination.clone());
+            match Stream::connect(&destination) {
+                Ok(outbound) => {
+                    if self.set_outbound(outbound, event_loop) {
+                        return true;
+                    }
+                },
+                Err(e) => {
+                    warn!("Failed to connect {:?} to {}, {:?}", self.inb.token, destination, e);
+                    self.backends.failed(&destination);
+                },
+            }
+        }
+
+        error!("Giving up on {:?}: no more destinations to try", self.inb.token);
+        false
+    }
+
+    fn set_destination(&mut self, destination: Addr) {
+        if let Some(ref previous) = self.destination {
+            self.backends.remove(previous);
+        }
+        self.backends.add(destination.clone());
+        self.destination = Some(destination);
+    }
+
+    fn set_outbound(&mut self, outbound: Stream, event_loop: &mut EventLoop<Nexus>) -> bool {
     
-        let mut out_conn = Conn::new(Token(OUTMASK + self.inb.token.as_usize()), outbound, bufsize);
-        out_conn.register(event_loop).ok().expect("TODO: handle registration failure");
+        let mut out_conn = Conn::new(Token(OUTMASK + self.inb.token.as_usize()), outbound, self.bufsize, false);
+        if let Err(e) = out_conn.register(event_loop) {
+            warn!("Failed to register outbound {:?} with event loop, {:?}", out_conn.token, e);
+            return false;
+        }
         self.out = Some(out_conn);
 
-        self.inb.reregister(event_loop).ok().expect("TODO: handle reregistration failure");
+        self.inb.reregister(event_loop).is_ok()
+    }
 
-        true
+    /// Give up waiting for the client after `ms`, see `timed_out`.
+    pub fn wait(&mut self, ms: u64, event_loop: &mut EventLoop<Nexus>) {
+        match event_loop.timeout_ms(Timer::Flow(self.inb.token), ms) {
+            Ok(timeout) => self.timeout = Some(timeout),
+            Err(e) => warn!("Failed to set timeout for {:?}, waiting for the client as long as it takes, {:?}",
+                            self.inb.token, e),
+        }
+    }
+
+    /// Cancel the timeout (if any), so it does not fire for whatever flow gets our token next.
+    pub fn clear_timeout(&mut self, event_loop: &mut EventLoop<Nexus>) {
+        if let Some(timeout) = self.timeout.take() {
+            event_loop.clear_timeout(timeout);
+        }
+    }
+
+    /// Handle the timeout set by `wait`: let the multiplexer decide with what the client sent so far.
+    ///
+    /// Returns false if the flow should be stopped.
+    pub fn timed_out(&mut self, multiplexer: Option<&Box<Multiplexer>>, event_loop: &mut EventLoop<Nexus>) -> bool {
+        self.timeout = None;
+        if self.expect_proxy {
+            warn!("Giving up on {:?}: no PROXY protocol header in time", self.inb.token);
+            return false;
+        }
+        match multiplexer {
+            Some(multiplexer) => {
+                let mr = multiplexer.timed_out(&self.inb.buf.bytes());
+                self.route(mr, event_loop)
+            },
+            None => {
+                warn!("[timed_out] Listener of {:?} is gone, aborting!", self.inb.token);
+                false
+            },
+        }
+    }
+
+    pub fn token(&self) -> Token {
+        self.inb.token
+    }
+
+    /// What the API shows about this flow.
+    pub fn info(&self) -> FlowInfo {
+        FlowInfo {
+            client: self.client.map(|addr| addr.to_string()),
+            destination: self.destination.as_ref().map(|addr| addr.to_string()),
+            fingerprint: self.fingerprint.clone(),
+        }
+    }
+
+    /// Whether the destination of the flow has been picked, i.e. the multiplexer is no longer needed.
+    pub fn routed(&self) -> bool {
+        self.out.is_some() || self.resolving()
+    }
+
+    /// Whether the flow waits for the destination to be resolved, i.e. has no outbound connection yet.
+    pub fn resolving(&self) -> bool {
+        self.pool.is_some()
+    }
+
+    /// Whether the outbound connection has been initiated, but not yet established.
+    pub fn connecting(&self) -> bool {
+        match self.out {
+            Some(ref out) => !out.connected,
+            None => false,
+        }
     }
 
-    fn read0(&mut self, bufsize: usize, multiplexer: &Box<Multiplexer>, event_loop: &mut EventLoop<Nexus>) -> bool {
+    /// Handle the first events of an outbound connection that is still connecting.
+    ///
+    /// If the connection attempt failed, the next alternate destination (if any) is tried.
+    /// Returns false if the flow should be stopped.
+    pub fn check_connect(&mut self, events: EventSet, event_loop: &mut EventLoop<Nexus>) -> bool {
+        let result = match self.out {
+            Some(ref out) => out.sock.take_socket_error(),
+            None => return true,
+        };
+
+        match result {
+            Ok(_) if !events.is_error() && !events.is_hup() => {
+                if events.is_writable() {
+                    debug!("Connected {:?} to {:?}", self.inb.token, self.destination);
+                    if let Some(ref mut out) = self.out {
+                        out.connected = true;
+                        if let Some(ref header) = self.proxy_header {
+                            // ahead of anything from the client, a fresh socket buffer takes it all at once
+                            match out.sock.try_write(header) {
+                                Ok(Some(n)) if n == header.len() => (),
+                                result => {
+                                    warn!("Failed to send the PROXY protocol header of {:?}, {:?}", self.inb.token, result);
+                                    return false;
+                                },
+                            }
+                        }
+                    }
+                    if let Some(ref destination) = self.destination {
+                        self.backends.connected(destination);
+                    }
+                }
+                true
+            },
+            _ => {
+                warn!("Failed to connect {:?} to {:?}, {:?} {:?}", self.inb.token, self.destination, events, result);
+                if let Some(ref destination) = self.destination {
+                    self.backends.failed(destination);
+                }
+                self.connect_next(event_loop)
+            },
+        }
+    }
+
+    fn read0(&mut self, multiplexer: Option<&Box<Multiplexer>>, event_loop: &mut EventLoop<Nexus>) -> bool {
+    let multiplexer = match multiplexer {
+        Some(multiplexer) => multiplexer,
+        None => {
+            warn!("[read0] Listener of {:?} is gone, aborting!", self.inb.token);
+            return false;
+        },
+    };
+
     loop {
         match self.inb.sock.try_read_buf(&mut self.inb.buf) {
             Ok(Some(0)) => {
@@ -114,19 +393,17 @@ impl Flow {
             let remaining = <RingBuf as Buf>::remaining(&self.inb.buf);
                 debug!("[read0] Successfully read {} bytes from {:?}, buf size: {}",
                        n, self.inb.token, remaining);
-                
-                match multiplexer.destination(&self.inb.buf.bytes()) {
-                    MR::NeedMore => (), //continue reading
-                    MR::Mismatch => {
-                        debug!("[read0] Connection unrecognized, aborting!");
-                        self.inb.dead = true;
-                        return false;
-                    },
-                    MR::Match(destination) => {
-                        let destination_stream = TcpStream::connect(&destination).ok().expect(
-                            		"TODO: outbound connect failure not handled yet");
-                        return self.set_outbound(destination_stream, bufsize, event_loop);
-                    },
+
+                if self.expect_proxy && !self.read_proxy_header() {
+                    self.inb.dead = true;
+                    return false;
+                }
+
+                if !self.expect_proxy {
+                    match multiplexer.destination(&self.inb.buf.bytes()) {
+                        MR::NeedMore => (), //continue reading
+                        mr => return self.route(mr, event_loop),
+                    }
                 }
                 
                 if self.inb.buf.is_full() {
@@ -147,16 +424,112 @@ impl Flow {
     }
     }
 
+    /// Strip the PROXY protocol header off the inbound buffer (once it is all there), and take
+    /// the client and endpoint addresses from it.
+    ///
+    /// Returns false if the client did not send a proper header.
+    fn read_proxy_header(&mut self) -> bool {
+        let header = match proxy::parse(&self.inb.buf.bytes()) {
+            proxy::ParseResult::NeedMore => return true,
+            proxy::ParseResult::Malformed(e) => {
+                warn!("Giving up on {:?} from {}: {}", self.inb.token, self.client.map(|addr| addr.to_string()).unwrap_or("?".to_string()), e);
+                return false;
+            },
+            proxy::ParseResult::Header(header) => header,
+        };
+
+        debug!("{:?} from {:?} is a connection from {:?} to {:?}", self.inb.token, self.client, header.source, header.destination);
+        if header.source.is_some() {
+            self.client = header.source;
+            self.endpoint = header.destination;
+        }
+        <RingBuf as Buf>::advance(&mut self.inb.buf, header.len);
+        self.expect_proxy = false;
+
+        if !self.acl.permits(self.client.map(|addr| addr.ip())) {
+            self.deny("listener");
+            return false;
+        }
+        true
+    }
+
+    /// Count and log a client that is not allowed, by the ACL of the listener or of its route.
+    fn deny(&mut self, by: &str) {
+        self.denied.set(self.denied.get() + 1);
+        info!("Denied {:?} from {} by the {} ({} denied so far)", self.inb.token,
+              self.client.map(|addr| addr.to_string()).unwrap_or("?".to_string()), by, self.denied.get());
+    }
+
+    /// Act on the decision of the multiplexer. Returns false if the flow should be stopped.
+    pub fn route(&mut self, mr: MR, event_loop: &mut EventLoop<Nexus>) -> bool {
+        self.clear_timeout(event_loop);
+
+        match mr {
+            MR::NeedMore => {
+                // the client has started talking (see ProtocolPlexer::timed_out), keep reading
+                true
+            },
+            MR::Mismatch => {
+                debug!("[route] Connection unrecognized, aborting!");
+                self.inb.dead = true;
+                false
+            },
+            MR::Reject(response) => {
+                debug!("[route] Connection rejected, aborting!");
+                // best effort, the socket buffer is surely empty at this point
+                if let Err(e) = self.inb.sock.try_write(response) {
+                    debug!("[route] Failed to respond to {:?}, {:?}", self.inb.token, e);
+                }
+                self.inb.dead = true;
+                false
+            },
+            MR::Match(pool, fingerprint) => {
+                if let Some(ref fingerprint) = fingerprint {
+                    info!("{:?} from {}: JA3 {} JA4 {}", self.inb.token, self.client.map(|addr| addr.to_string()).unwrap_or("?".to_string()),
+                          fingerprint.ja3, fingerprint.ja4);
+                }
+                self.fingerprint = fingerprint;
+                if !pool.destinations().acl.permits(self.client.map(|addr| addr.ip())) {
+                    self.deny("route");
+                    self.inb.dead = true;
+                    return false;
+                }
+                if let Some(version) = pool.destinations().send_proxy {
+                    self.proxy_header = Some(self.proxy_header(version));
+                }
+                self.connect_pool(pool, event_loop)
+            },
+        }
+    }
+
+    /// The PROXY protocol header telling the backends about the client,
+    /// and for v2 about what the client asked for in its TLS ClientHello (if that is what it sent).
+    fn proxy_header(&self, version: proxy::Version) -> Vec<u8> {
+        let hello = match version {
+            proxy::Version::V2 => match tls::parse_client_hello(&self.inb.buf.bytes(), self.bufsize) {
+                tls::ParseResult::Hello(hello) => Some(hello),
+                _ => None,
+            },
+            proxy::Version::V1 => None,
+        };
+        let server_name = hello.as_ref().and_then(|hello| hello.server_names.first().map(|name| &name[..]));
+        let alpn = hello.as_ref().and_then(|hello| hello.alpn.first().map(|protocol| &protocol[..]));
+
+        proxy::header(version, self.client, self.endpoint, server_name, alpn)
+    }
+
     /// Handle flow read event from event loop.
     ///
     #[inline]
-    pub fn read(&mut self, inbo: bool, bufsize: usize, multiplexer: &Box<Multiplexer>, event_loop: &mut EventLoop<Nexus>) -> bool {
+    pub fn read(&mut self, inbo: bool, multiplexer: Option<&Box<Multiplexer>>, event_loop: &mut EventLoop<Nexus>) -> bool {
 
         if inbo {
             match self.out {
                 Some(ref mut peer) => read1(&mut self.inb, peer, event_loop),
+                // leave it to the outbound connection, whose registration re-arms the inbound one
+                None if self.pool.is_some() => true,
                 None => {
-                    self.read0(bufsize, multiplexer, event_loop)
+                    self.read0(multiplexer, event_loop)
                 },
             }
         } else {
@@ -197,6 +570,14 @@ impl Flow {
     }
 }
 
+impl Drop for Flow {
+    fn drop(&mut self) {
+        if let Some(ref destination) = self.destination {
+            self.backends.remove(destination);
+        }
+    }
+}
+
 fn read1(conn: &mut Conn, peer: &mut Conn, event_loop: &mut EventLoop<Nexus>) -> bool {
     loop {
         match conn.sock.try_read_buf(&mut conn.buf) {
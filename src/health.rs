@@ -0,0 +1,151 @@
+use std::io;
+use mio::*;
+use rand;
+use rand::Rng;
+
+use tls;
+use stream::{Addr, Stream};
+
+use {Nexus, Timer};
+
+/// Default for the consecutive connect failures that take a backend out of rotation.
+pub const MAX_FAILS: usize = 3;
+
+/// Default for how long a backend stays out of rotation after max_fails, in ms.
+pub const FAIL_TIMEOUT: u64 = 10000;
+
+/// Default for the time between two rounds of health checks, in ms.
+pub const CHECK_INTERVAL: u64 = 5000;
+
+/// Default for how long a backend has to pass a health check, in ms.
+pub const CHECK_TIMEOUT: u64 = 2000;
+
+// max size of the answer to the ClientHello of a TLS health check
+const MAX_ANSWER: usize = 1024;
+
+/// How a health check tells that a backend is up.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Check {
+    /// It accepts TCP connections.
+    Tcp,
+    /// It answers a ClientHello (with this SNI, if any) with a ServerHello.
+    Tls(Option<String>),
+}
+
+/// The active health checks of the backends of a [[listen]] section.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    pub check: Check,
+    // between two rounds of checks, in ms
+    pub interval: u64,
+    // for a backend to pass a check, in ms
+    pub timeout: u64,
+}
+
+/// A health check of a backend in progress.
+pub struct Probe {
+    pub backend: Addr,
+
+    sock: Stream,
+
+    // token used to register the socket with the event loop
+    token: Token,
+
+    check: Check,
+
+    // whether the socket is known to be connected
+    connected: bool,
+
+    // what the backend answered so far (TLS checks)
+    buf: Vec<u8>,
+
+    // pending until the check passes or fails
+    timeout: Option<Timeout>,
+}
+
+impl Probe {
+    /// A check of the backend, which `sock` is connecting to.
+    pub fn new(backend: Addr, sock: Stream, token: Token, check: Check) -> Probe {
+        Probe {
+            backend: backend,
+            sock: sock,
+            token: token,
+            check: check,
+            connected: false,
+            buf: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    /// Register the socket with the event loop, giving the backend `ms` to pass.
+    pub fn register(&mut self, ms: u64, event_loop: &mut EventLoop<Nexus>) -> io::Result<()> {
+        try!(event_loop.register(&self.sock, self.token, EventSet::all(), PollOpt::edge()));
+        match event_loop.timeout_ms(Timer::Probe(self.token), ms) {
+            Ok(timeout) => self.timeout = Some(timeout),
+            Err(e) => warn!("Failed to set timeout for the health check of {}, {:?}", self.backend, e),
+        }
+        Ok(())
+    }
+
+    /// Cancel the timeout (if any), so it does not fire for whatever probe gets our token next.
+    pub fn clear_timeout(&mut self, event_loop: &mut EventLoop<Nexus>) {
+        if let Some(timeout) = self.timeout.take() {
+            event_loop.clear_timeout(timeout);
+        }
+    }
+
+    /// The timeout set by `register` fired.
+    pub fn timed_out(&mut self) {
+        self.timeout = None;
+    }
+
+    /// Handle events of the socket: None while the check goes on, then whether it passed.
+    pub fn ready(&mut self, events: EventSet) -> Option<Result<(), String>> {
+        if !self.connected {
+            match self.sock.take_socket_error() {
+                Err(e) => return Some(Err(format!("{}", e))),
+                Ok(_) if events.is_error() => return Some(Err(format!("{:?}", events))),
+                Ok(_) if events.is_writable() => self.connected = true,
+                Ok(_) => return None,
+            }
+
+            match self.check {
+                Check::Tcp => return Some(Ok(())),
+                Check::Tls(ref server_name) => {
+                    let mut random = [0; 32];
+                    rand::thread_rng().fill_bytes(&mut random);
+                    let hello = tls::client_hello(server_name.as_ref().map(|name| &name[..]), &random);
+                    // a fresh socket buffer takes it all at once
+                    match self.sock.try_write(&hello) {
+                        Ok(Some(n)) if n == hello.len() => (),
+                        Ok(_) => return Some(Err("ClientHello not sent".to_string())),
+                        Err(e) => return Some(Err(format!("Failed to send ClientHello, {}", e))),
+                    }
+                },
+            }
+        }
+
+        if events.is_readable() {
+            let mut buf = [0; MAX_ANSWER];
+            loop {
+                match self.sock.try_read(&mut buf[..MAX_ANSWER - self.buf.len()]) {
+                    Ok(Some(0)) => return Some(Err("Closed without a ServerHello".to_string())),
+                    Ok(Some(n)) => {
+                        self.buf.extend_from_slice(&buf[..n]);
+                        if let Some(result) = tls::check_server_hello(&self.buf) {
+                            return Some(result);
+                        }
+                    },
+                    Ok(None) => break,
+                    Err(e) => return Some(Err(format!("{}", e))),
+                }
+            }
+        }
+
+        if events.is_hup() || events.is_error() {
+            return Some(Err(format!("Closed without a ServerHello, {:?}", events)));
+        }
+
+        None
+    }
+}
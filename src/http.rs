@@ -0,0 +1,209 @@
+/// Default for the max size of the head (request line and headers) of an HTTP request.
+pub const MAX_HEADER_SIZE: usize = 8192;
+
+/// What clients get for requests that cannot be routed.
+pub const BAD_REQUEST: &'static [u8] = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// Outcome of inspecting the first bytes of an inbound connection.
+#[derive(Debug)]
+pub enum ParseResult {
+    /// The head of the request is not complete yet.
+    NeedMore,
+    /// Not an HTTP/1.x request, or one that does not make sense.
+    Invalid(String),
+    Request(Request),
+}
+
+/// The parts of the first request of a connection needed for routing.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    // value of the Host header, without the port
+    pub host: Option<String>,
+}
+
+/// Parses the head of the HTTP request at the start of an inbound connection.
+///
+/// Garbage is reported as soon as the request line gives it away,
+/// without waiting for the rest of the head.
+pub fn parse_request(buf: &[u8], max_size: usize) -> ParseResult {
+    let end = match find_end_of_head(buf) {
+        Some(end) if end <= max_size => end,
+        Some(_) => return ParseResult::Invalid(format!("request head exceeds the max of {} bytes", max_size)),
+        None => {
+            // the start of the request line is enough to tell garbage
+            let line_end = buf.iter().position(|b| *b == b'\n').unwrap_or(buf.len());
+            if let Err(reason) = check_method(&buf[..line_end]) {
+                return ParseResult::Invalid(reason);
+            }
+            if buf.len() >= max_size {
+                return ParseResult::Invalid(format!("request head exceeds the max of {} bytes", max_size));
+            }
+            return ParseResult::NeedMore;
+        },
+    };
+
+    match parse_head(&String::from_utf8_lossy(&buf[..end])) {
+        Ok(request) => ParseResult::Request(request),
+        Err(reason) => ParseResult::Invalid(reason),
+    }
+}
+
+/// Finds the empty line that ends the head, returns the offset after it.
+fn find_end_of_head(buf: &[u8]) -> Option<usize> {
+    for (i, b) in buf.iter().enumerate() {
+        if *b != b'\n' {
+            continue;
+        }
+        if buf[i + 1..].starts_with(b"\n") {
+            return Some(i + 2);
+        }
+        if buf[i + 1..].starts_with(b"\r\n") {
+            return Some(i + 3);
+        }
+    }
+    None
+}
+
+/// Checks the method token of a (possibly incomplete) request line.
+fn check_method(line: &[u8]) -> Result<(), String> {
+    for (i, b) in line.iter().enumerate() {
+        match *b {
+            b' ' if i > 0 => return Ok(()),
+            b'A' ... b'Z' | b'-' | b'_' => (),
+            _ => return Err("not an HTTP request".to_string()),
+        }
+    }
+    Ok(())
+}
+
+fn parse_head(head: &str) -> Result<Request, String> {
+    let mut lines = head.lines().map(|line| line.trim_right_matches('\r'));
+
+    let request_line = lines.next().unwrap_or("");
+    let parts: Vec<&str> = request_line.split(' ').collect();
+    if parts.len() != 3 || parts[1].is_empty() || !parts[2].starts_with("HTTP/") {
+        return Err(format!("bad request line {}", request_line));
+    }
+    try!(check_method(parts[0].as_bytes()));
+
+    let mut request = Request {
+        method: parts[0].to_string(),
+        target: parts[1].to_string(),
+        version: parts[2].to_string(),
+        host: None,
+    };
+
+    let mut hosts = 0;
+    for line in lines.take_while(|line| !line.is_empty()) {
+        if line.starts_with(" ") || line.starts_with("\t") {
+            return Err("obsolete line folding".to_string());
+        }
+        let colon = try!(line.find(':').ok_or(format!("bad header {}", line)));
+        let (name, value) = (&line[..colon], line[colon + 1..].trim());
+        if name.is_empty() || name.contains(' ') || name.contains('\t') {
+            return Err(format!("bad header {}", line));
+        }
+
+        if name.to_lowercase() == "host" {
+            hosts += 1;
+            if !value.is_empty() {
+                request.host = Some(strip_port(value).to_string());
+            }
+        }
+    }
+
+    if hosts > 1 {
+        // which one would the server believe?
+        return Err("more than one Host header".to_string());
+    }
+
+    Ok(request)
+}
+
+/// example.com:8080 is example.com, [::1]:8080 is [::1]
+fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') && host[i + 1..].chars().all(|c| c.is_digit(10)) => &host[..i],
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(head: &str) -> Request {
+        match parse_request(head.as_bytes(), MAX_HEADER_SIZE) {
+            ParseResult::Request(request) => request,
+            result => panic!("expected a request, got {:?}", result),
+        }
+    }
+
+    fn invalid(head: &str, max_size: usize) -> String {
+        match parse_request(head.as_bytes(), max_size) {
+            ParseResult::Invalid(reason) => reason,
+            result => panic!("expected an invalid request, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn host() {
+        let get = request("GET /index.html HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n");
+        assert_eq!(get.method, "GET");
+        assert_eq!(get.target, "/index.html");
+        assert_eq!(get.version, "HTTP/1.1");
+        assert_eq!(get.host, Some("example.com".to_string()));
+
+        assert_eq!(request("GET / HTTP/1.0\n\n").host, None);
+        assert_eq!(request("GET / HTTP/1.1\r\nHost:\r\n\r\n").host, None);
+    }
+
+    #[test]
+    fn host_with_port() {
+        assert_eq!(request("GET / HTTP/1.1\r\nHost: example.com:8080\r\n\r\n").host, Some("example.com".to_string()));
+        assert_eq!(request("GET / HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n").host, Some("[::1]".to_string()));
+        assert_eq!(request("GET / HTTP/1.1\r\nHost: [::1]\r\n\r\n").host, Some("[::1]".to_string()));
+        assert_eq!(request("GET / HTTP/1.1\r\nHost: 10.0.0.1:80\r\n\r\n").host, Some("10.0.0.1".to_string()));
+    }
+
+    #[test]
+    fn duplicate_host() {
+        assert_eq!(invalid("GET / HTTP/1.1\r\nHost: a.example.com\r\nHost: b.example.com\r\n\r\n", MAX_HEADER_SIZE),
+                   "more than one Host header");
+        assert_eq!(invalid("GET / HTTP/1.1\r\nHost: example.com\r\nhost: example.com\r\n\r\n", MAX_HEADER_SIZE),
+                   "more than one Host header");
+    }
+
+    #[test]
+    fn obsolete_line_folding() {
+        assert_eq!(invalid("GET / HTTP/1.1\r\nHost: example.com\r\n  .evil.com\r\n\r\n", MAX_HEADER_SIZE),
+                   "obsolete line folding");
+        assert_eq!(invalid("GET / HTTP/1.1\r\nX-Folded: a\r\n\tb\r\n\r\n", MAX_HEADER_SIZE), "obsolete line folding");
+    }
+
+    #[test]
+    fn head_over_the_limit() {
+        let head = format!("GET / HTTP/1.1\r\nHost: example.com\r\nX-Padding: {}\r\n\r\n", vec!["x"; 100].concat());
+        assert!(request(&head).host.is_some());
+        assert_eq!(invalid(&head, 100), "request head exceeds the max of 100 bytes");
+
+        // without waiting for the end of the head
+        let incomplete = &head[..head.len() - 4];
+        match parse_request(incomplete.as_bytes(), MAX_HEADER_SIZE) {
+            ParseResult::NeedMore => (),
+            result => panic!("expected NeedMore, got {:?}", result),
+        }
+        assert_eq!(invalid(incomplete, 100), "request head exceeds the max of 100 bytes");
+    }
+
+    #[test]
+    fn not_http() {
+        assert_eq!(invalid("\x16\x03\x01", MAX_HEADER_SIZE), "not an HTTP request");
+        assert_eq!(invalid("get / HTTP/1.1\r\n\r\n", MAX_HEADER_SIZE), "not an HTTP request");
+        assert_eq!(invalid("GET /\r\n\r\n", MAX_HEADER_SIZE), "bad request line GET /");
+    }
+}
@@ -0,0 +1,294 @@
+use std::cell::Cell;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::rc::Rc;
+use mio::*;
+use mio::tcp::TcpListener;
+use mio::unix::UnixListener;
+use net2::TcpBuilder;
+use net2::unix::UnixTcpBuilderExt;
+
+use acl::{Acl, Cidr};
+use multiplex::Multiplexer;
+use health::HealthCheck;
+use stream::{Addr, Stream};
+
+use {Nexus, Timer};
+
+/// A listening endpoint, i.e. one [[listen]] section of the config file.
+///
+/// Each listener accepts inbound connections on its own socket and hands them
+/// over to its own multiplexer.
+pub struct Listener {
+    // name of the [[listen]] section
+    pub name: String,
+
+    // server socket (that accepts inbound connections)
+    pub acceptor: Acceptor,
+
+    // token used to register the acceptor with the event loop
+    pub token: Token,
+
+    // size for the buffer between the inbound/outbound streams
+    pub bufsize: usize,
+
+    pub multiplexer: Box<Multiplexer>,
+
+    // whether clients start with a PROXY protocol header (see Flow::read0)
+    pub accept_proxy: bool,
+
+    // the only peers that may send it
+    pub trusted_proxies: Vec<Cidr>,
+
+    // clients that may connect
+    pub acl: Rc<Acl>,
+
+    // whether accepting is suspended because max_connections was reached
+    pub paused: bool,
+
+    // number of inbound connections refused because max_connections was reached
+    pub refused: usize,
+
+    // number of inbound connections denied by the ACL of the listener or of a route
+    // (shared with the flows, which are denied by routes)
+    pub denied: Rc<Cell<usize>>,
+
+    // of the backends of the multiplexer (if any)
+    pub health_check: Option<HealthCheck>,
+
+    // pending until the next round of health checks
+    check_timer: Option<Timeout>,
+}
+
+impl Listener {
+    pub fn new(name: String, acceptor: Acceptor, token: Token, bufsize: usize,
+               multiplexer: Box<Multiplexer>, accept_proxy: bool, trusted_proxies: Vec<Cidr>, acl: Acl,
+               health_check: Option<HealthCheck>) -> Listener {
+        Listener {
+            name: name,
+            acceptor: acceptor,
+            token: token,
+            bufsize: bufsize,
+            multiplexer: multiplexer,
+            accept_proxy: accept_proxy,
+            trusted_proxies: trusted_proxies,
+            acl: Rc::new(acl),
+            paused: false,
+            refused: 0,
+            denied: Rc::new(Cell::new(0)),
+            health_check: health_check,
+            check_timer: None,
+        }
+    }
+
+    /// Register the acceptor with the event loop.
+    pub fn register(&self, event_loop: &mut EventLoop<Nexus>) -> io::Result<()> {
+        event_loop.register(
+            &self.acceptor,
+            self.token,
+            EventSet::readable(),
+            PollOpt::edge(), // | PollOpt::oneshot()
+        ).or_else(|e| {
+            error!("[{}] Failed to register acceptor {:?}, {:?}", self.name, self.token, e);
+            Err(e)
+        })
+    }
+
+    /// Run the next round of health checks in `ms` (instead of whenever it was due).
+    pub fn schedule_checks(&mut self, ms: u64, event_loop: &mut EventLoop<Nexus>) {
+        self.cancel_checks(event_loop);
+        match event_loop.timeout_ms(Timer::HealthCheck(self.token), ms) {
+            Ok(timeout) => self.check_timer = Some(timeout),
+            Err(e) => error!("[{}] Failed to schedule health checks, {:?}", self.name, e),
+        }
+    }
+
+    pub fn cancel_checks(&mut self, event_loop: &mut EventLoop<Nexus>) {
+        if let Some(timeout) = self.check_timer.take() {
+            event_loop.clear_timeout(timeout);
+        }
+    }
+
+    /// The timer set by `schedule_checks` fired.
+    pub fn checks_due(&mut self) {
+        self.check_timer = None;
+    }
+}
+
+/// A server socket, listening on a TCP port or a Unix domain socket.
+pub enum Acceptor {
+    Tcp(TcpListener),
+    Unix(UnixListener, Addr),
+}
+
+impl Acceptor {
+    /// Accept a pending connection, along with the address of the client (if any), or None if there is none.
+    pub fn accept(&self) -> io::Result<Option<(Stream, Option<SocketAddr>)>> {
+        match *self {
+            Acceptor::Tcp(ref listener) => listener.accept()
+                .map(|accepted| accepted.map(|(sock, addr)| (Stream::Tcp(sock), Some(addr)))),
+            Acceptor::Unix(ref listener, _) => listener.accept()
+                .map(|accepted| accepted.map(|sock| (Stream::Unix(sock), None))),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<Addr> {
+        match *self {
+            Acceptor::Tcp(ref listener) => listener.local_addr().map(Addr::Inet),
+            Acceptor::Unix(_, ref addr) => Ok(addr.clone()),
+        }
+    }
+
+    /// Remove the socket file of a Unix domain socket that stopped listening.
+    ///
+    /// The workers share the socket: the first one to stop removes the file, the others find it gone.
+    pub fn unlink(&self) {
+        if let Acceptor::Unix(_, Addr::Unix(ref path)) = *self {
+            match remove_socket(path) {
+                Ok(()) => debug!("Removed socket file {}", path.display()),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => warn!("Failed to remove socket file {}, {:?}", path.display(), e),
+            }
+        }
+    }
+}
+
+impl Evented for Acceptor {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        match *self {
+            Acceptor::Tcp(ref listener) => listener.register(selector, token, interest, opts),
+            Acceptor::Unix(ref listener, _) => listener.register(selector, token, interest, opts),
+        }
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        match *self {
+            Acceptor::Tcp(ref listener) => listener.reregister(selector, token, interest, opts),
+            Acceptor::Unix(ref listener, _) => listener.reregister(selector, token, interest, opts),
+        }
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        match *self {
+            Acceptor::Tcp(ref listener) => listener.deregister(selector),
+            Acceptor::Unix(ref listener, _) => listener.deregister(selector),
+        }
+    }
+}
+
+/// Bind the server sockets of the given endpoint, one per worker.
+///
+/// With several workers, the TCP sockets are all bound to the same endpoint with SO_REUSEPORT,
+/// and the kernel spreads inbound connections among them. A Unix domain socket is bound once
+/// (replacing a stale socket file left by a previous run, but not one still in use) and shared
+/// by the workers.
+///
+/// With `shared`, a TCP socket is bound once and shared too, so that a worker that stops
+/// accepting (see Overload::Backlog) leaves new connections to the others instead of keeping
+/// the ones the kernel already queued on its own socket.
+pub fn bind(endpoint: &str, workers: usize, shared: bool) -> io::Result<Vec<Acceptor>> {
+    let addr = try!(Addr::parse(endpoint).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("Failed to parse server endpoint {}", endpoint))
+    }));
+
+    match addr {
+        Addr::Inet(addr) if shared => {
+            let listener = try!(bind_tcp(&addr, false));
+            let mut acceptors = Vec::new();
+            for _ in 1..workers {
+                acceptors.push(Acceptor::Tcp(try!(listener.try_clone())));
+            }
+            acceptors.push(Acceptor::Tcp(listener));
+            Ok(acceptors)
+        },
+        Addr::Inet(addr) => (0..workers).map(|_| bind_tcp(&addr, workers > 1).map(Acceptor::Tcp)).collect(),
+        Addr::Unix(ref path) => {
+            let listener = try!(bind_unix(path));
+            let mut acceptors = Vec::new();
+            for _ in 1..workers {
+                acceptors.push(Acceptor::Unix(try!(listener.try_clone()), addr.clone()));
+            }
+            acceptors.push(Acceptor::Unix(listener, addr.clone()));
+            Ok(acceptors)
+        },
+    }
+}
+
+fn bind_tcp(addr: &SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    let sock = try!(match *addr {
+        SocketAddr::V4(..) => TcpBuilder::new_v4(),
+        SocketAddr::V6(..) => TcpBuilder::new_v6(),
+    });
+
+    try!(sock.reuse_address(true));
+    if reuse_port {
+        try!(sock.reuse_port(true));
+    }
+    try!(sock.bind(addr));
+
+    let listener = try!(sock.listen(1024));
+    TcpListener::from_listener(listener, addr)
+}
+
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    // a socket somebody is still listening on is not ours to take over
+    if fs::symlink_metadata(path).is_ok() && UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                  format!("{} is already in use", path.display())));
+    }
+    match remove_socket(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        result => try!(result),
+    }
+    UnixListener::bind(path)
+}
+
+/// Remove the socket file at `path`, if it is one.
+fn remove_socket(path: &Path) -> io::Result<()> {
+    let metadata = try!(fs::symlink_metadata(path));
+    // only ever remove a socket, not whatever else the path may be a typo for
+    if metadata.file_type().is_socket() {
+        fs::remove_file(path)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io;
+
+    #[test]
+    fn replaces_only_stale_sockets() {
+        let path = env::temp_dir().join("tnexus-listener-test.sock");
+        let _ = fs::remove_file(&path);
+        let endpoint = format!("unix:{}", path.display());
+
+        let acceptors = bind(&endpoint, 2, false).unwrap();
+        assert_eq!(acceptors.len(), 2);
+        assert_eq!(bind(&endpoint, 1, false).err().map(|e| e.kind()), Some(io::ErrorKind::AddrInUse));
+
+        // closing the socket leaves the file behind, as a crash would
+        drop(acceptors);
+        assert!(path.exists());
+        let acceptors = bind(&endpoint, 1, false).unwrap();
+
+        acceptors[0].unlink();
+        assert!(!path.exists());
+        // already gone
+        acceptors[0].unlink();
+
+        // not a socket
+        fs::File::create(&path).unwrap();
+        assert!(bind(&endpoint, 1, false).is_err());
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -9,99 +9,222 @@ extern crate log;
 
 extern crate env_logger;
 
+extern crate nix;
+extern crate net2;
+extern crate regex;
+extern crate rand;
+
 extern crate rustc_serialize;
 
 use std::io;
-use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use std::cmp;
+use std::os::unix::io::AsRawFd;
+use std::rc::Rc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::sync::mpsc::channel;
 use mio::*;
-use mio::tcp::TcpListener;
-use mio::tcp::TcpStream;
 use mio::util::Slab;
+use nix::sys::socket;
+use nix::sys::socket::sockopt;
 
 const INVALID: Token = Token(0);
 const ACCEPTOR: Token = Token(1);
-const FLOW: Token = Token(2);
+
+// we can deal with a max of 64 listening endpoints
+pub const MAX_LISTENERS: usize = 64;
+const FLOW: Token = Token(1 + MAX_LISTENERS);
+
+// the flow slab starts this big and doubles whenever it fills up (up to max_connections)
+const INITIAL_CONNECTIONS: usize = 128;
 
 const OUTMASK: usize = 2147483648; //2 ** 31
 const NOTMASK: usize = !OUTMASK;
 
+// health checks in progress take the tokens from here on (flows stay below)
+const PROBE: Token = Token(1073741824); //2 ** 30
+
+// max number of health checks in progress
+const MAX_PROBES: usize = 1024;
+
 const EMPTY_BUF: [u8; 0] = []; 
 
 mod api;
 mod config;
 mod tls;
+mod http;
+mod protocol;
+mod fingerprint;
+mod acl;
+mod proxy;
+mod pool;
+mod dns;
+mod health;
 mod multiplex;
+mod stream;
 mod flow;
+mod listener;
+mod reload;
+mod cli;
 
-use multiplex::{Multiplexer, MR};
+use multiplex::MR;
 
-use api::ApiMsg;
+use api::{ApiMsg, BackendInfo, FlowInfo, Stats};
 
 use flow::Flow;
 
-pub struct Nexus {
-    // main server socket (that accepts inbound connections)
-    acceptor: TcpListener,
+use stream::{Addr, Stream};
+
+use pool::Backends;
 
-    // token of the acceptor
-    token: Token,
+use dns::Resolver;
+
+use health::{HealthCheck, Probe};
+
+use listener::{Acceptor, Listener};
+
+use config::{Endpoint, Overload};
+
+use reload::{Reload, Supervisor, WorkerGuard};
+
+use cli::Command;
+
+pub struct Nexus {
+    // server sockets (that accept inbound connections), one per [[listen]] section
+    listeners: Slab<Listener>,
 
     // a list of all inbound and outbound connections
     conns: Slab<Flow>,
 
-    // size for the buffer between the inbound/outbound streams 
-    bufsize: usize,
+    // max number of concurrent flows
+    max_connections: usize,
 
-    multiplexer: Box<Multiplexer>,
+    // max_connections of the config, which every worker gets a share of
+    configured_max_connections: usize,
+
+    // what to do with inbound connections beyond max_connections
+    overload: Overload,
+
+    // flows and health of each destination
+    backends: Backends,
+
+    // addresses of the host names in destinations
+    resolver: Resolver,
+
+    // health checks in progress
+    probes: Slab<Probe>,
+
+    // tokens of the flows and probes stopped since the last poll of the event loop,
+    // whose remaining events of that poll are not for whoever gets the token next
+    stale: Vec<Token>,
+}
+
+/// What a timeout of the event loop is for.
+pub enum Timer {
+    /// A flow waiting for its client to speak first.
+    Flow(Token),
+    /// The next round of health checks of a listener.
+    HealthCheck(Token),
+    /// A health check taking too long.
+    Probe(Token),
 }
 
 impl Nexus {
-    fn new(acceptor: TcpListener, bufsize: usize, multiplexer: Box<Multiplexer>) -> Nexus {
-        Nexus {
-            acceptor: acceptor,
+    fn new(max_connections: usize, configured_max_connections: usize, overload: Overload, max_fails: usize,
+           fail_timeout: u64, resolver: Resolver) -> Nexus {
+        // the token of a flow should stay below the ones of the probes
+        let max_connections = cmp::min(max_connections, PROBE.as_usize() - FLOW.as_usize());
 
+        Nexus {
             // I don't use Token(0) because kqueue will send stuff to Token(0)
             // by default causing really strange behavior. This way, if I see
             // something as Token(0), I know there are kqueue shenanigans
             // going on.
-            token: ACCEPTOR,
+            listeners: Slab::new_starting_at(ACCEPTOR, MAX_LISTENERS),
+
+            // listeners take Token(1) .. Token(64), so start after that
+            conns: Slab::new_starting_at(FLOW, cmp::min(INITIAL_CONNECTIONS, max_connections)),
+
+            max_connections: max_connections,
+
+            configured_max_connections: configured_max_connections,
+
+            overload: overload,
+
+            backends: Backends::new(max_fails, fail_timeout),
 
-            // SERVER is Token(1), so start after that
-            // we can deal with a max of 126 connections
-            conns: Slab::new_starting_at(FLOW, 128),
+            resolver: resolver,
 
-            bufsize: bufsize,
+            probes: Slab::new_starting_at(PROBE, MAX_PROBES),
 
-            multiplexer: multiplexer,
+            stale: Vec::new(),
         }
     }
 
-    /// Register Server with the event loop.
+    /// Register the endpoint's (already bound) server socket with the event loop.
     ///
     /// This keeps the registration details neatly tucked away inside of our implementation.
-    fn register(&mut self, event_loop: &mut EventLoop<Nexus>) -> io::Result<()> {
-        event_loop.register(
-            &self.acceptor,
-            self.token,
-            EventSet::readable(),
-            PollOpt::edge(), // | PollOpt::oneshot()
-        ).or_else(|e| {
-            error!("Failed to register server {:?}, {:?}", self.token, e);
-            Err(e)
-        })
+    fn listen(&mut self, endpoint: &Endpoint, acceptor: Acceptor, event_loop: &mut EventLoop<Nexus>) -> io::Result<Token> {
+        let multiplexer = endpoint.multiplexer();
+        let health_check = endpoint.health_check.clone();
+
+        let token = match self.listeners.insert_with(|token| {
+            Listener::new(endpoint.name.clone(), acceptor, token, endpoint.bufsize, multiplexer, endpoint.accept_proxy,
+                          endpoint.trusted_proxies.clone(), endpoint.acl.clone(), health_check)
+        }) {
+            Some(token) => token,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "Too many listening endpoints")),
+        };
+
+        // Start listening for incoming connections
+        match self.listeners[token].register(event_loop) {
+            Ok(_) => {
+                info!("[{}] Listening on {}", endpoint.name, endpoint.listen);
+                if endpoint.health_check.is_some() {
+                    self.listeners[token].schedule_checks(0, event_loop);
+                }
+                Ok(token)
+            },
+            Err(e) => {
+                self.listeners.remove(token);
+                Err(e)
+            }
+        }
     }
 
     /// Accept a _new_ client connection.
     ///
     /// The server will keep track of the new connection and forward any events from the event loop
     /// to this connection.
-    fn accept(&mut self, event_loop: &mut EventLoop<Nexus>) {
-        debug!("tnexus accepting new socket(s)");
+    fn accept(&mut self, listener_token: Token, event_loop: &mut EventLoop<Nexus>) {
+        debug!("tnexus accepting new socket(s) on {:?}", listener_token);
+
+        let listener = match self.listeners.get_mut(listener_token) {
+            Some(listener) => listener,
+            None => {
+                warn!("Unknown listener token {:?}", listener_token);
+                return;
+            }
+        };
+
+        let was_paused = listener.paused;
+        listener.paused = false;
 
         loop {
+        if self.conns.count() >= self.max_connections && self.overload == Overload::Backlog {
+            // leave new connections in the backlog, stop_flow will get back to them
+            if !was_paused {
+                warn!("[{}] Reached max_connections ({}), not accepting new connections for now",
+                      listener.name, self.max_connections);
+            }
+            listener.paused = true;
+            return;
+        }
+
         // Log an error if there is no socket, but otherwise move on so we do not tear down the
         // entire server.
-        let inbound = match self.acceptor.accept() {
+        let inbound = match listener.acceptor.accept() {
             Ok(s) => {
                 match s {
                     Some(sock) => sock,
@@ -112,34 +235,79 @@ impl Nexus {
                 }
             },
             Err(e) => {
-                error!("Failed to accept new socket, {:?}", e);
+                error!("[{}] Failed to accept new socket, {:?}", listener.name, e);
                 return;
             }
         };
         
         let inbound_stream = inbound.0;
 
-        let bufsize = self.bufsize;
+        // behind a load balancer, the client is known once the PROXY protocol header is read (see Flow::read0),
+        // which only the load balancer gets to send
+        let peer = inbound.1.map(|addr| addr.ip());
+        let denied_by = if listener.accept_proxy && !acl::trusts(&listener.trusted_proxies, peer) {
+            Some("not a trusted proxy")
+        } else if !listener.accept_proxy && !listener.acl.permits(peer) {
+            Some("by the ACL")
+        } else {
+            None
+        };
+        if let Some(reason) = denied_by {
+            listener.denied.set(listener.denied.get() + 1);
+            info!("[{}] Denied connection from {}, {} ({} denied so far)", listener.name,
+                  inbound.1.map(|addr| addr.to_string()).unwrap_or("?".to_string()), reason, listener.denied.get());
+            reset(&inbound_stream);
+            continue;
+        }
+
+        if self.conns.count() >= self.max_connections {
+            listener.refused += 1;
+            warn!("[{}] Reached max_connections ({}), refusing connection from {} ({} refused so far)",
+                  listener.name, self.max_connections, inbound.1.map(|addr| addr.to_string()).unwrap_or("?".to_string()),
+                  listener.refused);
+            reset(&inbound_stream);
+            continue;
+        }
+
+        if !self.conns.has_remaining() {
+            let grow_by = cmp::min(self.conns.count(), self.max_connections - self.conns.count());
+            info!("Growing connection slab by {} entries", grow_by);
+            self.conns.grow(grow_by);
+        }
+
+        let bufsize = listener.bufsize;
+        let accept_proxy = listener.accept_proxy;
+        let acl = listener.acl.clone();
+        let denied = listener.denied.clone();
+        let backends = self.backends.clone();
+        let resolver = self.resolver.clone();
 
         match self.conns.insert_with(|token| {
             debug!("Inserting {:?} into slab", token);
-            Flow::new(inbound_stream, token, bufsize)
+            Flow::new(inbound_stream, token, listener_token, bufsize, accept_proxy, acl, denied, backends, resolver)
         }) {
             Some(token) => {
-                match self.find_connection_by_token(token).inb.register(event_loop) {
+                match self.conns[token].inb.register(event_loop) {
                     Ok(_) => {
                         debug!("Registered inbound token {:?}", token);
 
-                        let mr = {
-                            self.multiplexer.destination(&EMPTY_BUF)
+                        let mr = if accept_proxy {
+                            // the multiplexer decides once the PROXY protocol header is read, see Flow::read0
+                            MR::NeedMore
+                        } else {
+                            listener.multiplexer.destination(&EMPTY_BUF)
                         };
                         match mr {
-                            MR::Match(outbound) => {
+                            mr @ MR::Match(..) => {
                                 //setup outbound immediately!
-                                let outbound_stream = TcpStream::connect(&outbound).ok().expect(
-                            		"TODO: outbound connect failure not handled yet");
-
-                                self.find_connection_by_token(token).set_outbound(outbound_stream, bufsize, event_loop);
+                                if !self.conns[token].route(mr, event_loop) {
+                                    self.conns.remove(token);
+                                }
+                            },
+                            MR::NeedMore => {
+                                if let Some(ms) = listener.multiplexer.wait_timeout() {
+                                    self.conns[token].wait(ms, event_loop);
+                                }
                             },
                             _ => (),
                         }
@@ -159,14 +327,207 @@ impl Nexus {
         }
     }
 
-    fn stop_flow(&mut self, token: Token) {
+    fn stop_flow(&mut self, token: Token, event_loop: &mut EventLoop<Nexus>) {
         debug!("Stopping flow {:?}", token);
-        self.conns.remove(token);
+        self.remove_flow(token, event_loop);
+
+        // a slot just became available, get back to the connections waiting in the backlog
+        self.resume_accepting(event_loop);
+    }
+
+    fn remove_flow(&mut self, token: Token, event_loop: &mut EventLoop<Nexus>) {
+        if let Some(mut flow) = self.conns.remove(token) {
+            flow.clear_timeout(event_loop);
+            self.stale.push(token);
+        }
+    }
+
+    fn resume_accepting(&mut self, event_loop: &mut EventLoop<Nexus>) {
+        let paused: Vec<Token> = self.listeners.iter()
+            .filter(|listener| listener.paused)
+            .map(|listener| listener.token)
+            .collect();
+        for listener_token in paused {
+            self.accept(listener_token, event_loop);
+        }
+    }
+
+    /// Close a listener, along with its flows that have not been routed yet.
+    ///
+    /// Established flows are left alone.
+    fn unlisten(&mut self, token: Token, event_loop: &mut EventLoop<Nexus>) {
+        let mut listener = match self.listeners.remove(token) {
+            Some(listener) => listener,
+            None => return,
+        };
+        if listener.health_check.is_some() {
+            listener.cancel_checks(event_loop);
+            self.backends.forget_checks(&pool::resolved_addrs(&listener.multiplexer.pools(), &self.resolver));
+        }
+
+        if let Err(e) = event_loop.deregister(&listener.acceptor) {
+            warn!("[{}] Failed to deregister acceptor {:?}, {:?}", listener.name, token, e);
+        }
+
+        let unrouted: Vec<Token> = self.conns.iter()
+            .filter(|flow| flow.listener == token && !flow.routed())
+            .map(|flow| flow.token())
+            .collect();
+        for flow_token in unrouted {
+            self.remove_flow(flow_token, event_loop);
+        }
+
+        listener.acceptor.unlink();
+        info!("[{}] Stopped listening on {:?}", listener.name, listener.acceptor.local_addr());
+    }
+
+    /// Apply a new configuration.
+    ///
+    /// Removed endpoints stop listening, new ones start, and the remaining ones get the new
+    /// routing and bufsize for their new flows.
+    fn reload(&mut self, reload: Reload, event_loop: &mut EventLoop<Nexus>) -> Result<(), String> {
+        self.max_connections = cmp::min(reload.max_connections, PROBE.as_usize() - FLOW.as_usize());
+        self.configured_max_connections = reload.configured_max_connections;
+        self.overload = reload.overload;
+        self.backends.set_policy(reload.max_fails, reload.fail_timeout);
+        self.resolver.set_ttl(reload.dns_ttl);
+
+        let removed: Vec<Token> = self.listeners.iter()
+            .filter(|listener| !reload.endpoints.iter().any(|&(ref endpoint, _)| endpoint.name == listener.name))
+            .map(|listener| listener.token)
+            .collect();
+        for token in removed {
+            self.unlisten(token, event_loop);
+        }
+
+        let mut errors = Vec::new();
+
+        for (endpoint, acceptor) in reload.endpoints {
+            let existing = self.find_listener_by_name(&endpoint.name).map(|listener| listener.token);
+
+            match (existing, acceptor) {
+                (Some(token), None) => {
+                    let listener = &mut self.listeners[token];
+                    // routes changed through the API are gone, as documented in tnexus.toml
+                    listener.multiplexer = endpoint.multiplexer();
+                    listener.bufsize = endpoint.bufsize;
+                    listener.accept_proxy = endpoint.accept_proxy;
+                    listener.trusted_proxies = endpoint.trusted_proxies.clone();
+                    listener.acl = Rc::new(endpoint.acl.clone());
+                    if listener.health_check != endpoint.health_check {
+                        listener.health_check = endpoint.health_check.clone();
+                        match listener.health_check {
+                            Some(_) => listener.schedule_checks(0, event_loop),
+                            None => {
+                                listener.cancel_checks(event_loop);
+                                self.backends.forget_checks(&pool::resolved_addrs(&listener.multiplexer.pools(), &self.resolver));
+                            },
+                        }
+                    }
+                    debug!("[{}] Reloaded", endpoint.name);
+                },
+                (existing, Some(acceptor)) => {
+                    if let Some(token) = existing {
+                        self.unlisten(token, event_loop);
+                    }
+                    if let Err(e) = self.listen(&endpoint, acceptor, event_loop) {
+                        errors.push(format!("[{}] Failed to listen on {}, {}", endpoint.name, endpoint.listen, e));
+                    }
+                },
+                (None, None) => {
+                    errors.push(format!("[{}] No server socket for {}", endpoint.name, endpoint.listen));
+                },
+            }
+        }
+
+        // max_connections or the policy may have changed
+        self.resume_accepting(event_loop);
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join(", "))
+        }
+    }
+
+    fn stats(&self, listener: &Listener) -> Stats {
+        Stats {
+            connections: self.conns.count(),
+            max_connections: self.configured_max_connections,
+            refused: listener.refused,
+            denied: listener.denied.get(),
+        }
     }
 
-    /// Find a connection in the slab using the given token.
-    fn find_connection_by_token<'a>(&'a mut self, token: Token) -> &'a mut Flow {
-        &mut self.conns[token]
+    /// Start a round of health checks of the backends of a listener, and schedule the next one.
+    ///
+    /// Host names not resolved yet are left for the next round.
+    fn check_health(&mut self, listener_token: Token, event_loop: &mut EventLoop<Nexus>) {
+        let (backends, health_check) = match self.listeners.get_mut(listener_token) {
+            Some(listener) => {
+                listener.checks_due();
+                match listener.health_check.clone() {
+                    Some(health_check) => {
+                        listener.schedule_checks(health_check.interval, event_loop);
+                        (pool::resolved_addrs(&listener.multiplexer.pools(), &self.resolver), health_check)
+                    },
+                    None => return,
+                }
+            },
+            None => return,
+        };
+
+        for backend in backends {
+            // still checking it (for this listener, or another one routing to it)
+            if self.probes.iter().any(|probe| probe.backend == backend) {
+                continue;
+            }
+            self.probe(backend, &health_check, event_loop);
+        }
+    }
+
+    fn probe(&mut self, backend: Addr, health_check: &HealthCheck, event_loop: &mut EventLoop<Nexus>) {
+        debug!("Checking the health of {}", backend);
+
+        let sock = match Stream::connect(&backend) {
+            Ok(sock) => sock,
+            Err(e) => {
+                self.backends.checked(&backend, Err(format!("{}", e)));
+                return;
+            },
+        };
+
+        let check = health_check.check.clone();
+        let token = match self.probes.insert_with(|token| Probe::new(backend.clone(), sock, token, check)) {
+            Some(token) => token,
+            None => {
+                warn!("Too many health checks in progress, skipping {}", backend);
+                return;
+            },
+        };
+
+        if let Err(e) = self.probes[token].register(health_check.timeout, event_loop) {
+            error!("Failed to register the health check of {} with event loop, {:?}", backend, e);
+            self.probes.remove(token);
+        }
+    }
+
+    fn end_probe(&mut self, token: Token, result: Result<(), String>, event_loop: &mut EventLoop<Nexus>) {
+        if let Some(mut probe) = self.probes.remove(token) {
+            probe.clear_timeout(event_loop);
+            self.stale.push(token);
+            debug!("Health check of {}: {:?}", probe.backend, result);
+            self.backends.checked(&probe.backend, result);
+        }
+    }
+
+    /// Find a listener in the slab using the name of its [[listen]] section.
+    fn find_listener_by_name<'a>(&'a self, name: &str) -> Option<&'a Listener> {
+        self.listeners.iter().find(|l| l.name == name)
+    }
+
+    fn find_listener_by_name_mut<'a>(&'a mut self, name: &str) -> Option<&'a mut Listener> {
+        self.listeners.iter_mut().find(|l| l.name == name)
     }
 
     /*
@@ -187,18 +548,109 @@ impl Nexus {
 }
 
 impl Handler for Nexus {
-    type Timeout = ();
+    type Timeout = Timer;
     type Message = ApiMsg;
 
     fn notify(&mut self, evloop: &mut EventLoop<Nexus>, msg: ApiMsg) {
         match msg {
-            ApiMsg::SniRequest(tx) => {
-                let send_result = tx.send(ApiMsg::SniResponse(self.multiplexer.map()));
+            ApiMsg::SniRequest(name, tx) => {
+                let sni_map = match self.find_listener_by_name(&name) {
+                    Some(listener) => listener.multiplexer.map(),
+                    None => {
+                        warn!("API request for unknown listener {}", name);
+                        BTreeMap::new()
+                    }
+                };
+                let send_result = tx.send(ApiMsg::SniResponse(sni_map));
+                if send_result.is_err() {
+                    error!("Failed responding to ApiMsg");
+                }
+            },
+            ApiMsg::StatsRequest(name, tx) => {
+                let stats = match self.find_listener_by_name(&name) {
+                    Some(listener) => self.stats(listener),
+                    None => {
+                        warn!("API request for unknown listener {}", name);
+                        Stats::default()
+                    }
+                };
+                let send_result = tx.send(ApiMsg::StatsResponse(stats));
                 if send_result.is_err() {
                     error!("Failed responding to ApiMsg");
                 }
             },
+            ApiMsg::FlowsRequest(name, tx) => {
+                let flows: Vec<FlowInfo> = match self.find_listener_by_name(&name) {
+                    Some(listener) => self.conns.iter()
+                        .filter(|flow| flow.listener == listener.token)
+                        .map(|flow| flow.info())
+                        .collect(),
+                    None => {
+                        warn!("API request for unknown listener {}", name);
+                        Vec::new()
+                    }
+                };
+                if tx.send(ApiMsg::FlowsResponse(flows)).is_err() {
+                    error!("Failed responding to ApiMsg");
+                }
+            },
+            ApiMsg::HealthRequest(name, tx) => {
+                let backends: Vec<BackendInfo> = match self.find_listener_by_name(&name) {
+                    Some(listener) => pool::resolved_addrs(&listener.multiplexer.pools(), &self.resolver).iter()
+                        .map(|backend| self.backends.info(backend))
+                        .collect(),
+                    None => {
+                        warn!("API request for unknown listener {}", name);
+                        Vec::new()
+                    }
+                };
+                if tx.send(ApiMsg::HealthResponse(backends)).is_err() {
+                    error!("Failed responding to ApiMsg");
+                }
+            },
+            ApiMsg::RouteSet(name, routes, tx) => {
+                let result = match self.find_listener_by_name_mut(&name) {
+                    Some(listener) => {
+                        routes.into_iter()
+                            .map(|(sni, destinations)| listener.multiplexer.set_route(&sni, destinations))
+                            .fold(Ok(true), |acc, result| acc.and(result.map(|_| true)))
+                    },
+                    None => Err(format!("Unknown listener {}", name)),
+                };
+                if tx.send(ApiMsg::RouteResponse(result)).is_err() {
+                    error!("Failed responding to ApiMsg");
+                }
+            },
+            ApiMsg::RouteDelete(name, sni, tx) => {
+                let result = match self.find_listener_by_name_mut(&name) {
+                    Some(listener) => listener.multiplexer.remove_route(&sni),
+                    None => Err(format!("Unknown listener {}", name)),
+                };
+                if tx.send(ApiMsg::RouteResponse(result)).is_err() {
+                    error!("Failed responding to ApiMsg");
+                }
+            },
+            ApiMsg::Reload(reload, tx) => {
+                let result = self.reload(reload, evloop);
+                if tx.send(ApiMsg::ReloadResponse(result)).is_err() {
+                    error!("Failed responding to ApiMsg");
+                }
+            },
+            ApiMsg::Resolved(host, result) => {
+                for token in self.resolver.resolved(host, result) {
+                    let resumed = match self.conns.get_mut(token) {
+                        Some(flow) => flow.resolved(evloop),
+                        None => true,
+                    };
+                    if !resumed {
+                        self.stop_flow(token, evloop);
+                    }
+                }
+            },
             ApiMsg::Quit(_) => {
+                for listener in self.listeners.iter() {
+                    listener.acceptor.unlink();
+                }
                 evloop.shutdown();
             },
             _ => warn!("Bad request from API"),
@@ -206,6 +658,39 @@ impl Handler for Nexus {
         //event_loop.shutdown();
     }
 
+    fn timeout(&mut self, event_loop: &mut EventLoop<Nexus>, timer: Timer) {
+        let token = match timer {
+            Timer::Flow(token) => token,
+            Timer::HealthCheck(listener_token) => {
+                self.check_health(listener_token, event_loop);
+                return;
+            },
+            Timer::Probe(token) => {
+                if let Some(probe) = self.probes.get_mut(token) {
+                    probe.timed_out();
+                }
+                self.end_probe(token, Err("Timed out".to_string()), event_loop);
+                return;
+            },
+        };
+        debug!("Timeout for {:?}", token);
+
+        let should_stop = match self.conns.get_mut(token) {
+            // cleared timeouts never fire, but better safe than sorry
+            Some(ref flow) if flow.routed() => false,
+            Some(flow) => {
+                let multiplexer = self.listeners.get(flow.listener)
+                    .map(|listener| &listener.multiplexer);
+                !flow.timed_out(multiplexer, event_loop)
+            },
+            None => false,
+        };
+
+        if should_stop {
+            self.stop_flow(token, event_loop);
+        }
+    }
+
     fn ready(&mut self, event_loop: &mut EventLoop<Nexus>, _token: Token, events: EventSet) {
         assert!(_token != INVALID, "[BUG]: Received event for Token(0)");
 
@@ -215,15 +700,64 @@ impl Handler for Nexus {
         
         debug!("READY {:X} {} {:?} {:?}", tokval, inb, token, events);
 
+        if self.stale.contains(&token) {
+            debug!("Ignoring stale event for {:X}", tokval);
+            return;
+        }
+
+        if token >= PROBE {
+            let result = match self.probes.get_mut(token) {
+                Some(probe) => probe.ready(events),
+                None => None,
+            };
+            if let Some(result) = result {
+                self.end_probe(token, result, event_loop);
+            }
+            return;
+        }
+
         if events.is_error() {
             warn!("Final event {:?} for token {:X}", events, tokval);
         }
 
-        // We never expect a write event for our `Server` token . A write event for any other token
+        // The first event for an outbound connection tells us whether connect() succeeded.
+        if !inb {
+            let connect_result = match self.conns.get_mut(token) {
+                // a stale event of a stopped flow, whose token went to a new flow (not connecting yet)
+                Some(ref flow) if !flow.routed() || flow.resolving() => {
+                    debug!("Ignoring stale event for {:X}", tokval);
+                    return;
+                },
+                Some(flow) => {
+                    if flow.connecting() {
+                        Some(flow.check_connect(events, event_loop))
+                    } else {
+                        None
+                    }
+                },
+                None => None,
+            };
+
+            match connect_result {
+                Some(false) => {
+                    self.stop_flow(token, event_loop);
+                    return;
+                },
+                Some(true) => {
+                    if self.conns[token].connecting() {
+                        // still connecting (possibly to an alternate destination)
+                        return;
+                    }
+                },
+                None => (),
+            }
+        }
+
+        // We never expect a write event for our listener tokens. A write event for any other token
         // should be handed off to that connection.
         if events.is_writable() {
             debug!("Write event for {:X}", tokval);
-            assert!(self.token != token, "Received writable event for Server");
+            assert!(token >= FLOW, "Received writable event for listener");
 
             let write_result = match self.conns.get_mut(token) {
                 None => {
@@ -236,16 +770,16 @@ impl Handler for Nexus {
             };
 
             if !write_result {
-                self.stop_flow(token);
+                self.stop_flow(token, event_loop);
                 return;
             }
         }
         
-        // A read event for our `Server` token means we are establishing a new connection. A read
+        // A read event for a listener token means we are establishing a new connection. A read
         // event for any other token should be handed off to that connection.
         if events.is_readable() {
-            if ACCEPTOR == token {
-                self.accept(event_loop);
+            if token < FLOW {
+                self.accept(token, event_loop);
             } else {
                 let should_stop = match self.conns.get_mut(token) {
                     None => {
@@ -253,7 +787,11 @@ impl Handler for Nexus {
                         false
                     },
                     Some(flow) => {
-                        if flow.read(inb, self.bufsize, &self.multiplexer, event_loop) {
+                        // established flows outlive their listener
+                        let multiplexer = self.listeners.get(flow.listener)
+                            .map(|listener| &listener.multiplexer);
+
+                        if flow.read(inb, multiplexer, event_loop) {
                             false
                         } else {
                             warn!("Read returned false for token: {:?}", token);
@@ -263,55 +801,122 @@ impl Handler for Nexus {
                 };
             
                 if should_stop {
-                    self.stop_flow(token);
+                    self.stop_flow(token, event_loop);
                 }
             }
         }
     }
+
+    fn tick(&mut self, _event_loop: &mut EventLoop<Nexus>) {
+        // the next poll only reports events of the current sockets
+        self.stale.clear();
+    }
+}
+
+/// Close the socket with a RST instead of the usual FIN handshake.
+fn reset(sock: &Stream) {
+    let linger = socket::linger { l_onoff: 1, l_linger: 0 };
+    if let Err(e) = socket::setsockopt(sock.as_raw_fd(), sockopt::Linger, &linger) {
+        warn!("Failed to set SO_LINGER, the connection will be closed normally {:?}", e);
+    }
 }
 
 fn main() {
-    env_logger::init().ok().expect("Failed to init logger");
+    let options = cli::parse();
+
+    match options.log_level {
+        Some(level) => env_logger::LogBuilder::new().filter(None, level).init(),
+        None => env_logger::init(),
+    }.ok().expect("Failed to init logger");
+
+    match options.command {
+        Command::PrintDefaultConfig => {
+            print!("{}", config::DEFAULT_CONFIG);
+            return;
+        },
+        Command::CheckConfig => {
+            config::check_config(options.config.as_ref().map(|path| &path[..]), options.overrides);
+        },
+        Command::Run => (),
+    }
 
     info!("Starting tnexus...");
 
-    // Create an event loop
-    let mut event_loop = EventLoop::new()
-        .ok().expect("Could not initialize MIO event loop");
-        
-    let main_channel = event_loop.channel();
-    
-    let args = config::configure(main_channel);
+    let mut config = config::configure(options.config.as_ref().map(|path| &path[..]), options.overrides);
 
-    debug!("args: {:?}", args);
+    // the main thread takes care of reloading the config, on SIGHUP or when asked by an API
+    let (control_tx, control_rx) = channel();
+    reload::watch_sighup(control_tx.clone());
 
-    let endpoint_addr: SocketAddr = args.listen.parse()
-        .ok().expect("Failed to parse server enpoint");
+    // Create an event loop per worker
+    let event_loops: Vec<EventLoop<Nexus>> = (0..config.workers).map(|_| {
+        EventLoop::new().ok().expect("Could not initialize MIO event loop")
+    }).collect();
 
-    // Setup the acceptor socket
-    let acceptor = TcpListener::bind(&endpoint_addr)
-        .ok().expect("Failed to bind server endpoint");
+    let channels: Vec<Sender<ApiMsg>> = event_loops.iter().map(|event_loop| event_loop.channel()).collect();
 
-    let mut nexus = Nexus::new(
-        acceptor,
-        args.bufsize,
-        args.destination);
+    let apis = config::start_apis(&mut config, channels.clone(), control_tx.clone()).or_else(|e| {
+        error!("Failed to start API, {}", e);
+        Err(e)
+    }).ok().expect("Failed to start API");
 
-    // Start listening for incoming connections
-    nexus.register(&mut event_loop)
-        .ok().expect("Failed to register acceptor in event loop");
-    
-    // Start handling events
-    event_loop.run(&mut nexus)
-        .ok().expect("Failed to start event loop");
+    debug!("config: {:?}", config);
 
-    info!("Main loop complete.");
-    
-    if args.api.is_some() {
-        info!("Shutting down API...");
-        args.api.unwrap().thread_handle.close().ok().expect("Could not shutdown hyper API");
-        info!("Shutting down API: ok.");
+    let max_connections = config.worker_max_connections();
+    let configured_max_connections = config.max_connections;
+    let overload = config.overload;
+    let (max_fails, fail_timeout) = (config.max_fails, config.fail_timeout);
+    let dns_ttl = config.dns_ttl;
+
+    // every worker gets its own server socket for each endpoint (see listener::bind)
+    let mut acceptors: Vec<Vec<Acceptor>> = (0..config.workers).map(|_| Vec::new()).collect();
+    for endpoint in config.endpoints.iter() {
+        let bound = listener::bind(&endpoint.listen, config.workers, config.overload == Overload::Backlog).or_else(|e| {
+            error!("[{}] Failed to bind server endpoint {}, {:?}", endpoint.name, endpoint.listen, e);
+            Err(e)
+        }).ok().expect("Failed to bind server endpoint");
+        for (worker_acceptors, acceptor) in acceptors.iter_mut().zip(bound.into_iter()) {
+            worker_acceptors.push(acceptor);
+        }
     }
 
+    let workers: Vec<JoinHandle<()>> = event_loops.into_iter().zip(acceptors.into_iter()).enumerate()
+        .map(|(i, (mut event_loop, acceptors))| {
+        let endpoints = config.endpoints.clone();
+
+        let guard = WorkerGuard(control_tx.clone());
+
+        thread::Builder::new().name(format!("worker-{}", i)).spawn(move || {
+            let _guard = guard;
+            let resolver = Resolver::new(event_loop.channel(), dns_ttl);
+            let mut nexus = Nexus::new(max_connections, configured_max_connections, overload, max_fails, fail_timeout, resolver);
+
+            // Start listening for incoming connections
+            for (endpoint, acceptor) in endpoints.iter().zip(acceptors.into_iter()) {
+                nexus.listen(endpoint, acceptor, &mut event_loop)
+                    .ok().expect("Failed to setup listening endpoint");
+            }
+
+            // Start handling events
+            event_loop.run(&mut nexus)
+                .ok().expect("Failed to start event loop");
+
+            info!("Worker {} main loop complete.", i);
+        }).ok().expect("Could not start worker thread")
+    }).collect();
+
+    let mut supervisor = Supervisor::new(config, channels, apis, control_tx);
+    supervisor.run(control_rx);
+
+    for worker in workers {
+        if worker.join().is_err() {
+            error!("Worker thread panicked");
+        }
+    }
+
+    info!("Main loop complete.");
+
+    supervisor.shutdown();
+
     info!("Over.");
 }
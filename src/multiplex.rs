@@ -1,80 +1,694 @@
-use std::net::SocketAddr;
-use std::collections::BTreeMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
+use std::rc::Rc;
+
+use regex::Regex;
 
 use tls;
+use tls::ParseResult;
+use http;
+use protocol;
+use protocol::Detected;
+use fingerprint;
+use fingerprint::Fingerprint;
+use pool::{Destinations, Pool};
 
 pub enum MR {
     NeedMore,
     Mismatch,
-    Match(SocketAddr),
+    /// The backends of the route, one of which the flow picks to connect to.
+    /// For TLS connections, also the fingerprint of the client.
+    Match(Rc<Pool>, Option<Fingerprint>),
+    /// Drop the connection after sending this response.
+    Reject(&'static [u8]),
 }
 
 pub trait Multiplexer : Debug {
     fn destination(&self, &[u8]) -> MR;
-    fn map(&self) -> BTreeMap<String, String>;
+    fn map(&self) -> BTreeMap<String, Destinations>;
+
+    /// Adds or replaces the route for the given name (only affects new connections).
+    fn set_route(&mut self, name: &str, destinations: Destinations) -> Result<(), String>;
+
+    /// Removes the route for the given name, returns false if there was no such route.
+    fn remove_route(&mut self, name: &str) -> Result<bool, String>;
+
+    /// Every pool of backends it may route to, e.g. for health checks
+    /// (see pool::resolved_addrs for their addresses).
+    fn pools(&self) -> Vec<Rc<Pool>>;
+
+    /// How long to wait for the first bytes of a client (in ms) before asking `timed_out`,
+    /// None to wait as long as it takes.
+    fn wait_timeout(&self) -> Option<u64> {
+        None
+    }
+
+    /// Where to send a client that has not said enough within `wait_timeout`.
+    fn timed_out(&self, _buf: &[u8]) -> MR {
+        MR::Mismatch
+    }
 }
 
 #[derive(Debug)]
 pub struct FixedPlexer {
-    destination_addr: SocketAddr,
+    destination: Rc<Pool>,
+}
+
+/// Routes by name, for the multiplexers that route by host name.
+///
+/// Names are normalized and their destinations parsed when routes are set, so finding the route
+/// of a connection takes lookups by (slices of) its host name, without parsing anything, nor
+/// allocating unless the name has to be lowercased.
+#[derive(Debug, Default)]
+struct Routes {
+    // by (normalized) exact name
+    exact: HashMap<String, Route>,
+
+    // by suffix, e.g. ".example.com" for *.example.com
+    wildcards: HashMap<String, Route>,
+
+    // regex routes like ~^api[0-9]+\.example\.com$, compiled, in the order they are tried
+    // (alphabetical order of the patterns)
+    regexes: Vec<(Regex, String, Route)>,
+}
+
+/// The routes of an exact name, a wildcard or a regex.
+#[derive(Debug, Default)]
+struct Route {
+    // for any ALPN protocol
+    any: Option<Rc<Pool>>,
+
+    // by ALPN protocol, e.g. for example.com@acme-tls/1
+    alpn: HashMap<String, Rc<Pool>>,
 }
 
 #[derive(Debug)]
 pub struct SniPlexer {
-    default_addr: String,
-    sni_map: BTreeMap<String, String>
+    // where to send ClientHellos without SNI or with an unknown SNI
+    default_addr: Rc<Pool>,
+
+    // where to send non-TLS traffic and unparseable ClientHellos
+    // (if None, such connections are dropped)
+    mismatch_addr: Option<Rc<Pool>>,
+
+    // routes by server name
+    routes: Routes,
+
+    // ClientHellos bigger than this are treated as unparseable
+    max_hello_size: usize,
+
+    // routes by client fingerprint, which beat the ones by server name
+    fingerprints: fingerprint::Rules<Rc<Pool>>,
+}
+
+#[derive(Debug)]
+pub struct HttpHostPlexer {
+    // where to send requests without a Host header or with an unknown host
+    default_addr: Rc<Pool>,
+
+    // routes by the Host header, like the ones of SniPlexer (but without ALPN protocols)
+    routes: Routes,
+
+    // requests with a bigger head (request line and headers) are rejected
+    max_header_size: usize,
+}
+
+#[derive(Debug)]
+pub struct ProtocolPlexer {
+    // multiplexer per protocol (see protocol::PROTOCOLS), e.g. an SniPlexer for tls
+    protocols: BTreeMap<String, Box<Multiplexer>>,
+
+    // where to send unknown protocols, and clients that stay silent for wait_timeout
+    // (servers speak first in some protocols, like SSH)
+    // (if None, such connections are dropped)
+    default_addr: Option<Rc<Pool>>,
+
+    // in ms
+    wait_timeout: u64,
 }
 
 impl FixedPlexer {
-    pub fn new(addr: &str) -> FixedPlexer {
+    pub fn new(destinations: Destinations) -> FixedPlexer {
         FixedPlexer {
-            destination_addr: addr.parse().ok().expect("Failed to parse destination enpoint")
+            destination: pool(destinations),
         }
     }
 }
 
 impl Multiplexer for FixedPlexer {
     fn destination(&self, _buf: &[u8]) -> MR {
-        MR::Match(self.destination_addr)
+        MR::Match(self.destination.clone(), None)
     }
     
-    fn map(&self) -> BTreeMap<String, String> {
+    fn map(&self) -> BTreeMap<String, Destinations> {
         BTreeMap::new()
     }
+
+    fn set_route(&mut self, _name: &str, _destinations: Destinations) -> Result<(), String> {
+        Err("Routes can only be changed for SNI multiplexing endpoints".to_string())
+    }
+
+    fn remove_route(&mut self, _name: &str) -> Result<bool, String> {
+        Err("Routes can only be changed for SNI multiplexing endpoints".to_string())
+    }
+
+    fn pools(&self) -> Vec<Rc<Pool>> {
+        vec![self.destination.clone()]
+    }
 }
 
 impl SniPlexer {
-    pub fn new(default_addr: &str, sni_map: BTreeMap<String, String>) -> SniPlexer {
-        SniPlexer {
-            default_addr: default_addr.to_string(),
-            sni_map: sni_map,
+    pub fn new(default_addr: Destinations, mismatch_addr: Option<Destinations>, sni_map: BTreeMap<String, Destinations>,
+               max_hello_size: usize, fingerprints: fingerprint::Rules<Destinations>) -> SniPlexer {
+        let mut plexer = SniPlexer {
+            default_addr: pool(default_addr),
+            mismatch_addr: mismatch_addr.map(pool),
+            routes: Routes::default(),
+            max_hello_size: max_hello_size,
+            fingerprints: fingerprints.map_routes(|destinations| pool(destinations.clone())),
+        };
+
+        for (name, destinations) in sni_map {
+            plexer.set_route(&name, destinations).ok().expect("Invalid SNI route");
         }
+
+        plexer
+    }
+
+    fn mismatch(&self) -> MR {
+        match self.mismatch_addr {
+            Some(ref destination) => MR::Match(destination.clone(), None),
+            None => MR::Mismatch,
+        }
+    }
+}
+
+impl HttpHostPlexer {
+    pub fn new(default_addr: Destinations, host_map: BTreeMap<String, Destinations>, max_header_size: usize) -> HttpHostPlexer {
+        let mut plexer = HttpHostPlexer {
+            default_addr: pool(default_addr),
+            routes: Routes::default(),
+            max_header_size: max_header_size,
+        };
+
+        for (name, destinations) in host_map {
+            plexer.set_route(&name, destinations).ok().expect("Invalid HTTP route");
+        }
+
+        plexer
     }
 }
 
+impl ProtocolPlexer {
+    pub fn new(protocols: BTreeMap<String, Box<Multiplexer>>, default_addr: Option<Destinations>,
+               wait_timeout: u64) -> ProtocolPlexer {
+        ProtocolPlexer {
+            protocols: protocols,
+            default_addr: default_addr.map(pool),
+            wait_timeout: wait_timeout,
+        }
+    }
+
+    fn default(&self) -> MR {
+        match self.default_addr {
+            Some(ref destination) => MR::Match(destination.clone(), None),
+            None => MR::Mismatch,
+        }
+    }
+}
+
+impl Routes {
+    /// Finds the route of a host name.
+    ///
+    /// An exact match wins, then the longest matching wildcard, then the first matching regex
+    /// (in alphabetical order of the patterns). For each of those, a route for one of the
+    /// offered ALPN protocols (in the client's order of preference) beats a route for any protocol.
+    fn route(&self, sname: &str, alpn: &[String]) -> Option<&Rc<Pool>> {
+        let name = normalize(sname);
+
+        if let Some(pool) = self.exact.get(&name[..]).and_then(|route| route.get(alpn)) {
+            return Some(pool);
+        }
+
+        // the first dot gives the longest suffix
+        for (i, _) in name.match_indices(".") {
+            if let Some(pool) = self.wildcards.get(&name[i..]).and_then(|route| route.get(alpn)) {
+                return Some(pool);
+            }
+        }
+
+        self.regexes.iter()
+            .filter(|&&(ref regex, _, _)| regex.is_match(&name))
+            .filter_map(|&(_, _, ref route)| route.get(alpn))
+            .next()
+    }
+
+    fn set(&mut self, name: &str, destinations: Destinations) -> Result<(), String> {
+        let regex = try!(validate_route(name));
+        let pool = Rc::new(try!(Pool::new(destinations)));
+
+        let (sname, protocol) = split_route(name);
+        let route = match regex {
+            Some(regex) => {
+                let pattern = sname.to_string();
+                let index = match self.regexes.iter().position(|&(_, ref p, _)| *p >= pattern) {
+                    Some(index) if self.regexes[index].1 == pattern => index,
+                    Some(index) => {
+                        self.regexes.insert(index, (regex, pattern, Route::default()));
+                        index
+                    },
+                    None => {
+                        self.regexes.push((regex, pattern, Route::default()));
+                        self.regexes.len() - 1
+                    },
+                };
+                &mut self.regexes[index].2
+            },
+            None if sname.starts_with("*.") => self.wildcards.entry(normalize(&sname[1..]).into_owned()).or_insert(Route::default()),
+            None => self.exact.entry(normalize(sname).into_owned()).or_insert(Route::default()),
+        };
+
+        match protocol {
+            Some(protocol) => { route.alpn.insert(protocol.to_string(), pool); },
+            None => route.any = Some(pool),
+        }
+        Ok(())
+    }
+
+    /// The routes, as configured (with normalized names).
+    fn destinations(&self) -> BTreeMap<String, Destinations> {
+        let mut result = BTreeMap::new();
+        let names = self.exact.iter().map(|(name, route)| (name.clone(), route))
+            .chain(self.wildcards.iter().map(|(suffix, route)| (format!("*{}", suffix), route)))
+            .chain(self.regexes.iter().map(|&(_, ref pattern, ref route)| (pattern.clone(), route)));
+        for (name, route) in names {
+            if let Some(ref pool) = route.any {
+                result.insert(name.clone(), pool.destinations().clone());
+            }
+            for (protocol, pool) in route.alpn.iter() {
+                result.insert(format!("{}@{}", name, protocol), pool.destinations().clone());
+            }
+        }
+        result
+    }
+
+    fn pools(&self) -> Vec<&Rc<Pool>> {
+        self.exact.values()
+            .chain(self.wildcards.values())
+            .chain(self.regexes.iter().map(|&(_, _, ref route)| route))
+            .flat_map(|route| route.any.iter().chain(route.alpn.values()))
+            .collect()
+    }
+
+    fn remove(&mut self, name: &str) -> bool {
+        let (sname, protocol) = split_route(name);
+        let removed = {
+            let route = if sname.starts_with("~") {
+                self.regexes.iter_mut().find(|&&mut (_, ref pattern, _)| pattern == sname).map(|&mut (_, _, ref mut route)| route)
+            } else if sname.starts_with("*.") {
+                self.wildcards.get_mut(&normalize(&sname[1..])[..])
+            } else {
+                self.exact.get_mut(&normalize(sname)[..])
+            };
+            match (route, protocol) {
+                (Some(route), Some(protocol)) => route.alpn.remove(protocol).is_some(),
+                (Some(route), None) => route.any.take().is_some(),
+                (None, _) => false,
+            }
+        };
+
+        // forget names that no longer have any route
+        self.exact.retain(|_, route| !route.is_empty());
+        self.wildcards.retain(|_, route| !route.is_empty());
+        self.regexes.retain(|&(_, _, ref route)| !route.is_empty());
+
+        removed
+    }
+}
+
+impl Route {
+    /// The route for the first of the offered ALPN protocols that has one, or else for any protocol.
+    fn get(&self, alpn: &[String]) -> Option<&Rc<Pool>> {
+        alpn.iter()
+            .filter_map(|protocol| self.alpn.get(protocol))
+            .next()
+            .or(self.any.as_ref())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.any.is_none() && self.alpn.is_empty()
+    }
+}
+
+/// Destinations of the config, validated by it already (see config::validate_destination).
+fn pool(destinations: Destinations) -> Rc<Pool> {
+    Rc::new(Pool::new(destinations).ok().expect("Invalid destination"))
+}
+
+/// Server names are case-insensitive (RFC 6066), and a trailing dot makes no difference.
+///
+/// Names that are already in lowercase ASCII, like nearly all of them, are borrowed.
+fn normalize<'a>(name: &'a str) -> Cow<'a, str> {
+    let name = name.trim_right_matches('.');
+    if name.bytes().any(|b| match b { b'A' ... b'Z' => true, _ => b >= 0x80 }) {
+        Cow::Owned(name.to_lowercase())
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+/// The server name of a route, as it is looked up (e.g. "example.com" for "Example.COM.@h2").
+pub fn route_server_name<'a>(name: &'a str) -> Cow<'a, str> {
+    normalize(split_route(name).0)
+}
+
+/// Splits a route name into the server name part and the ALPN protocol (if any).
+fn split_route(name: &str) -> (&str, Option<&str>) {
+    match name.rfind('@') {
+        Some(i) => (&name[..i], Some(&name[i + 1..])),
+        None => (name, None),
+    }
+}
+
+/// Checks an SNI route name, i.e. an exact server name, a wildcard (*.example.com)
+/// or a regex (prefixed with ~), optionally followed by @ and an ALPN protocol.
+///
+/// Returns the compiled regex for regex routes.
+pub fn validate_route(name: &str) -> Result<Option<Regex>, String> {
+    let (sname, protocol) = split_route(name);
+    if protocol == Some("") {
+        return Err(format!("Bad route {}, expected an ALPN protocol after @", name));
+    }
+
+    if sname.starts_with("~") {
+        return Regex::new(&format!("(?i){}", &sname[1..]))
+            .map(Some)
+            .map_err(|e| format!("Bad route regex {}: {}", name, e));
+    }
+
+    let host = if sname.starts_with("*.") { &sname[2..] } else { sname };
+    if host.is_empty() || host.contains("*") {
+        return Err(format!("Bad route {}, wildcards are only allowed as *.example.com", name));
+    }
+
+    Ok(None)
+}
+
+/// Checks an HTTP route name, which is like an SNI route (see `validate_route`) without an ALPN protocol.
+pub fn validate_host_route(name: &str) -> Result<(), String> {
+    if split_route(name).1.is_some() {
+        return Err(format!("Bad HTTP route {}, ALPN protocols are only known to TLS", name));
+    }
+    validate_route(name).map(|_| ())
+}
+
 impl Multiplexer for SniPlexer {
     fn destination(&self, buf: &[u8]) -> MR {
-        match tls::parse_tls_client_hello(buf) {
-            None => MR::NeedMore,
-            Some(result) => {
-                match result {
-                    None => MR::Mismatch,
-                    Some(sname) => {
-                        let destination: &str = self.sni_map.get(&sname).unwrap_or(&self.default_addr);
-
-                        let destination_addr: SocketAddr = destination.parse()
-                            .ok().expect("Failed to parse destination enpoint");
-
-                        MR::Match(destination_addr)
-                    },
+        match tls::parse_client_hello(buf, self.max_hello_size) {
+            ParseResult::NeedMore(n) => {
+                debug!("Need {} bytes of ClientHello, got {}", n, buf.len());
+                MR::NeedMore
+            },
+            ParseResult::NotTls => self.mismatch(),
+            ParseResult::Malformed(reason) => {
+                debug!("Malformed ClientHello: {}", reason);
+                self.mismatch()
+            },
+            ParseResult::Hello(hello) => {
+                debug!("{:?}", hello);
+                let fingerprint = Fingerprint::new(&hello);
+                if self.fingerprints.blocks(&fingerprint) {
+                    info!("Dropping blocked client: JA3 {} JA4 {}", fingerprint.ja3, fingerprint.ja4);
+                    return MR::Mismatch;
                 }
+
+                let destination = match (self.fingerprints.route(&fingerprint), hello.sni()) {
+                    (Some(destination), _) => destination,
+                    (None, Some(sname)) => self.routes.route(sname, &hello.alpn).unwrap_or(&self.default_addr),
+                    (None, None) => &self.default_addr,
+                };
+                MR::Match(destination.clone(), Some(fingerprint))
             },
         }
     }
     
-    fn map(&self) -> BTreeMap<String, String> {
-        self.sni_map.clone()
+    fn map(&self) -> BTreeMap<String, Destinations> {
+        self.routes.destinations()
+    }
+
+    fn set_route(&mut self, name: &str, destinations: Destinations) -> Result<(), String> {
+        self.routes.set(name, destinations)
+    }
+
+    fn remove_route(&mut self, name: &str) -> Result<bool, String> {
+        Ok(self.routes.remove(name))
+    }
+
+    fn pools(&self) -> Vec<Rc<Pool>> {
+        let mut pools = vec![&self.default_addr];
+        pools.extend(self.mismatch_addr.iter());
+        pools.extend(self.routes.pools());
+        pools.extend(self.fingerprints.routes.values());
+        pools.into_iter().cloned().collect()
+    }
+}
+
+impl Multiplexer for HttpHostPlexer {
+    fn destination(&self, buf: &[u8]) -> MR {
+        match http::parse_request(buf, self.max_header_size) {
+            http::ParseResult::NeedMore => MR::NeedMore,
+            http::ParseResult::Invalid(reason) => {
+                debug!("Invalid HTTP request: {}", reason);
+                MR::Reject(http::BAD_REQUEST)
+            },
+            http::ParseResult::Request(request) => {
+                debug!("{:?}", request);
+                let destination = match request.host {
+                    Some(ref host) => self.routes.route(host, &[]).unwrap_or(&self.default_addr),
+                    None => &self.default_addr,
+                };
+                MR::Match(destination.clone(), None)
+            },
+        }
+    }
+
+    fn map(&self) -> BTreeMap<String, Destinations> {
+        self.routes.destinations()
+    }
+
+    fn set_route(&mut self, name: &str, destinations: Destinations) -> Result<(), String> {
+        try!(validate_host_route(name));
+        self.routes.set(name, destinations)
+    }
+
+    fn remove_route(&mut self, name: &str) -> Result<bool, String> {
+        Ok(self.routes.remove(name))
+    }
+
+    fn pools(&self) -> Vec<Rc<Pool>> {
+        let mut pools = vec![&self.default_addr];
+        pools.extend(self.routes.pools());
+        pools.into_iter().cloned().collect()
+    }
+}
+
+/// Routes are those of the TLS multiplexer, the only one the API knows about.
+impl Multiplexer for ProtocolPlexer {
+    fn destination(&self, buf: &[u8]) -> MR {
+        match protocol::detect(buf) {
+            Detected::NeedMore => MR::NeedMore,
+            Detected::Protocol(protocol) => match self.protocols.get(protocol) {
+                Some(multiplexer) => multiplexer.destination(buf),
+                None => {
+                    debug!("No route for protocol {}", protocol);
+                    self.default()
+                },
+            },
+            Detected::Unknown => {
+                debug!("Unknown protocol");
+                self.default()
+            },
+        }
+    }
+
+    fn map(&self) -> BTreeMap<String, Destinations> {
+        self.protocols.get("tls").map(|multiplexer| multiplexer.map()).unwrap_or(BTreeMap::new())
+    }
+
+    fn set_route(&mut self, name: &str, destinations: Destinations) -> Result<(), String> {
+        match self.protocols.get_mut("tls") {
+            Some(multiplexer) => multiplexer.set_route(name, destinations),
+            None => Err("Routes can only be changed for SNI multiplexing endpoints".to_string()),
+        }
+    }
+
+    fn remove_route(&mut self, name: &str) -> Result<bool, String> {
+        match self.protocols.get_mut("tls") {
+            Some(multiplexer) => multiplexer.remove_route(name),
+            None => Err("Routes can only be changed for SNI multiplexing endpoints".to_string()),
+        }
+    }
+
+    fn pools(&self) -> Vec<Rc<Pool>> {
+        let mut pools: Vec<Rc<Pool>> = self.default_addr.iter().cloned().collect();
+        pools.extend(self.protocols.values().flat_map(|multiplexer| multiplexer.pools()));
+        pools
+    }
+
+    fn wait_timeout(&self) -> Option<u64> {
+        Some(self.wait_timeout)
+    }
+
+    fn timed_out(&self, buf: &[u8]) -> MR {
+        match protocol::detect(buf) {
+            // a slow client, but one that speaks first
+            Detected::Protocol(_) => self.destination(buf),
+            _ => {
+                debug!("Nothing recognizable after {} ms", self.wait_timeout);
+                self.default()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn destinations(backend: &str) -> Destinations {
+        Destinations::new(vec![backend.to_string()])
+    }
+
+    /// The backend a decision routes to, None for anything but a match.
+    fn backend(mr: MR) -> Option<String> {
+        match mr {
+            MR::Match(pool, _) => Some(pool.destinations().backends[0].clone()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn normalizes_names() {
+        assert_eq!(normalize("example.com"), "example.com");
+        assert_eq!(normalize("Example.COM."), "example.com");
+        assert_eq!(normalize("ÉXAMPLE.com"), "éxample.com");
+
+        match normalize("www.example.com.") {
+            Cow::Borrowed(name) => assert_eq!(name, "www.example.com"),
+            Cow::Owned(name) => panic!("{} was allocated", name),
+        }
     }
 
+    #[test]
+    fn protocol_timeouts() {
+        let mut protocols: BTreeMap<String, Box<Multiplexer>> = BTreeMap::new();
+        protocols.insert("ssh".to_string(), Box::new(FixedPlexer::new(destinations("127.0.0.1:22"))));
+        let plexer = ProtocolPlexer::new(protocols, Some(destinations("127.0.0.1:80")), 2000);
+
+        assert_eq!(plexer.wait_timeout(), Some(2000));
+        // a slow client, which got to say what it speaks
+        assert_eq!(backend(plexer.timed_out(b"SSH-2.0-OpenSSH_9.2p1\r\n")), Some("127.0.0.1:22".to_string()));
+        // nothing, part of a signature, or a protocol without a multiplexer
+        assert_eq!(backend(plexer.timed_out(b"")), Some("127.0.0.1:80".to_string()));
+        assert_eq!(backend(plexer.timed_out(b"SS")), Some("127.0.0.1:80".to_string()));
+        assert_eq!(backend(plexer.timed_out(b"\x16\x03\x01")), Some("127.0.0.1:80".to_string()));
+
+        let plexer = ProtocolPlexer::new(BTreeMap::new(), None, 2000);
+        match plexer.timed_out(b"") {
+            MR::Mismatch => (),
+            _ => panic!("expected Mismatch without a default"),
+        }
+    }
+
+    // each route sends to a backend of its own, to tell them apart
+    fn routes(names: &[&str]) -> Routes {
+        let mut routes = Routes::default();
+        for name in names.iter() {
+            routes.set(name, destinations(&format!("unix:/{}", name))).unwrap();
+        }
+        routes
+    }
+
+    /// The name of the route taken.
+    fn route(routes: &Routes, sname: &str, alpn: &[&str]) -> Option<String> {
+        let alpn: Vec<String> = alpn.iter().map(|protocol| protocol.to_string()).collect();
+        routes.route(sname, &alpn).map(|pool| pool.destinations().backends[0]["unix:/".len()..].to_string())
+    }
+
+    #[test]
+    fn route_precedence() {
+        let routes = routes(&["www.example.com", "www.example.com@h2", "mail.example.com",
+                              "*.example.com", "*.example.com@h2", "*.b.example.com",
+                              "~example", "~example@h2", "~^api[0-9]+\\."]);
+        let some = |name: &str| Some(name.to_string());
+
+        // exact names, whatever the case and trailing dot
+        assert_eq!(route(&routes, "www.example.com", &[]), some("www.example.com"));
+        assert_eq!(route(&routes, "WWW.Example.COM.", &[]), some("www.example.com"));
+        assert_eq!(route(&routes, "www.example.com", &["http/1.1"]), some("www.example.com"));
+        assert_eq!(route(&routes, "www.example.com", &["h2"]), some("www.example.com@h2"));
+        assert_eq!(route(&routes, "www.example.com", &["http/1.1", "h2"]), some("www.example.com@h2"));
+        // an exact name beats a wildcard, even one for the protocol
+        assert_eq!(route(&routes, "mail.example.com", &["h2"]), some("mail.example.com"));
+
+        // then the longest wildcard
+        assert_eq!(route(&routes, "x.example.com", &[]), some("*.example.com"));
+        assert_eq!(route(&routes, "x.example.com", &["h2"]), some("*.example.com@h2"));
+        assert_eq!(route(&routes, "a.b.example.com", &[]), some("*.b.example.com"));
+        assert_eq!(route(&routes, "a.b.example.com", &["h2"]), some("*.b.example.com"));
+        assert_eq!(route(&routes, "x.y.example.com", &[]), some("*.example.com"));
+
+        // then the first regex (by pattern)
+        assert_eq!(route(&routes, "www.example.org", &[]), some("~example"));
+        assert_eq!(route(&routes, "www.EXAMPLE.org", &["h2"]), some("~example@h2"));
+        assert_eq!(route(&routes, "api1.example.org", &[]), some("~^api[0-9]+\\."));
+        assert_eq!(route(&routes, "api1.example.org", &["h2"]), some("~^api[0-9]+\\."));
+
+        assert_eq!(route(&routes, "www.other.net", &["h2"]), None);
+    }
+
+    #[test]
+    fn wildcards_need_a_subdomain() {
+        let routes = routes(&["*.example.com"]);
+        assert_eq!(route(&routes, "www.example.com", &[]), Some("*.example.com".to_string()));
+        assert_eq!(route(&routes, "example.com", &[]), None);
+        assert_eq!(route(&routes, "example.com.", &[]), None);
+        assert_eq!(route(&routes, "wwwexample.com", &[]), None);
+        assert_eq!(route(&routes, "www.example.com.evil", &[]), None);
+    }
+
+    #[test]
+    fn remove_routes() {
+        let mut routes = routes(&["www.example.com", "www.example.com@h2", "*.example.com", "~example"]);
+
+        assert!(routes.remove("WWW.example.com.@h2"));
+        assert!(!routes.remove("www.example.com@h2"));
+        assert_eq!(route(&routes, "www.example.com", &["h2"]), Some("www.example.com".to_string()));
+        assert_eq!(routes.exact.len(), 1);
+
+        // the name goes along with its last route
+        assert!(routes.remove("www.example.com"));
+        assert!(routes.exact.is_empty());
+        assert_eq!(route(&routes, "www.example.com", &[]), Some("*.example.com".to_string()));
+
+        assert!(routes.remove("*.example.com"));
+        assert!(routes.wildcards.is_empty());
+        assert!(routes.remove("~example"));
+        assert!(routes.regexes.is_empty());
+
+        assert!(!routes.remove("www.example.com"));
+        assert!(!routes.remove("*.example.com@h2"));
+        assert_eq!(route(&routes, "www.example.com", &[]), None);
+    }
+
+    #[test]
+    fn configured_names() {
+        let routes = routes(&["Www.Example.com.", "www.example.com@h2", "*.EXAMPLE.com", "~^API", "~example@h2"]);
+        let names: Vec<String> = routes.destinations().keys().cloned().collect();
+        assert_eq!(names, vec!["*.example.com", "www.example.com", "www.example.com@h2", "~^API", "~example@h2"]);
+
+        assert_eq!(routes.destinations()["www.example.com@h2"], destinations("unix:/www.example.com@h2"));
+        assert_eq!(routes.pools().len(), 5);
+    }
 }
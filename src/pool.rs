@@ -0,0 +1,765 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use rand;
+use rand::Rng;
+use rustc_serialize::{Encodable, Encoder};
+
+use acl::Acl;
+use api::BackendInfo;
+use dns::{Lookup, Resolver};
+use proxy;
+use stream::{Addr, UNIX_PREFIX};
+
+// points per backend on the ring of the consistent-hash strategy
+const RING_POINTS: usize = 100;
+
+/// How a route picks the backend of a new connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    /// Always the first backend, the others only if connecting to it fails.
+    Failover,
+    RoundRobin,
+    /// Round-robin, in proportion to the weights of the backends.
+    Weighted,
+    /// The backend with the fewest flows (of the same worker).
+    LeastConnections,
+    Random,
+    /// By the IP address of the client, so the same client keeps getting the same backend
+    /// (and only the clients of a removed backend move when the pool changes).
+    ConsistentHash,
+}
+
+pub const STRATEGIES: [&'static str; 6] =
+    ["failover", "round-robin", "weighted", "least-connections", "random", "consistent-hash"];
+
+impl Strategy {
+    pub fn parse(name: &str) -> Result<Strategy, String> {
+        match name {
+            "failover" => Ok(Strategy::Failover),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "weighted" => Ok(Strategy::Weighted),
+            "least-connections" => Ok(Strategy::LeastConnections),
+            "random" => Ok(Strategy::Random),
+            "consistent-hash" => Ok(Strategy::ConsistentHash),
+            _ => Err(format!("Bad strategy {}, expected one of {}", name, STRATEGIES.join(", "))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Strategy::Failover => "failover",
+            Strategy::RoundRobin => "round-robin",
+            Strategy::Weighted => "weighted",
+            Strategy::LeastConnections => "least-connections",
+            Strategy::Random => "random",
+            Strategy::ConsistentHash => "consistent-hash",
+        }
+    }
+}
+
+/// Where a route sends its connections: "ip:port", "host:port" or "unix:/path" backends, and how to pick one of them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Destinations {
+    pub backends: Vec<String>,
+    pub strategy: Strategy,
+    // one per backend (only for Strategy::Weighted)
+    pub weights: Vec<usize>,
+    // PROXY protocol header to send to the backends (if any)
+    pub send_proxy: Option<proxy::Version>,
+    // clients that may take the route
+    pub acl: Acl,
+}
+
+impl Destinations {
+    /// Backends tried in order, i.e. a plain destination or an array of alternates.
+    pub fn new(backends: Vec<String>) -> Destinations {
+        Destinations {
+            backends: backends,
+            strategy: Strategy::Failover,
+            weights: Vec::new(),
+            send_proxy: None,
+            acl: Acl::default(),
+        }
+    }
+
+    /// A pool of backends, checking that the weights go with the strategy.
+    pub fn pool(backends: Vec<String>, strategy: Strategy, weights: Vec<usize>,
+                send_proxy: Option<proxy::Version>, acl: Acl) -> Result<Destinations, String> {
+        if backends.is_empty() {
+            return Err("Empty pool of backends".to_string());
+        }
+        match strategy {
+            Strategy::Weighted if weights.len() != backends.len() => {
+                return Err(format!("Expected {} weights, one per backend, got {}", backends.len(), weights.len()));
+            },
+            Strategy::Weighted if weights.iter().any(|weight| *weight == 0) => {
+                return Err("Weights should be positive".to_string());
+            },
+            Strategy::Weighted => (),
+            _ if !weights.is_empty() => {
+                return Err(format!("Weights are only for the weighted strategy, not {}", strategy.name()));
+            },
+            _ => (),
+        }
+
+        Ok(Destinations {
+            backends: backends,
+            strategy: strategy,
+            weights: weights,
+            send_proxy: send_proxy,
+            acl: acl,
+        })
+    }
+}
+
+/// Shown by the API like in the config: an array of alternates, or an object with
+/// the backends, strategy and weights of a pool (and the PROXY protocol version and
+/// allowed/denied clients, if any).
+impl Encodable for Destinations {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        if self.strategy == Strategy::Failover && self.send_proxy.is_none() && self.acl.is_empty() {
+            return self.backends.encode(s);
+        }
+
+        let optional = [!self.weights.is_empty(), self.send_proxy.is_some(), !self.acl.allow.is_empty(), !self.acl.deny.is_empty()];
+        let fields = 2 + optional.iter().filter(|present| **present).count();
+        s.emit_struct("Destinations", fields, |s| {
+            try!(s.emit_struct_field("backends", 0, |s| self.backends.encode(s)));
+            try!(s.emit_struct_field("strategy", 1, |s| s.emit_str(self.strategy.name())));
+            let mut index = 2;
+            if !self.weights.is_empty() {
+                try!(s.emit_struct_field("weights", index, |s| self.weights.encode(s)));
+                index += 1;
+            }
+            if let Some(version) = self.send_proxy {
+                try!(s.emit_struct_field("send_proxy_protocol", index, |s| s.emit_str(version.name())));
+                index += 1;
+            }
+            if !self.acl.allow.is_empty() {
+                let allow: Vec<String> = self.acl.allow.iter().map(|cidr| cidr.to_string()).collect();
+                try!(s.emit_struct_field("allow", index, |s| allow.encode(s)));
+                index += 1;
+            }
+            if !self.acl.deny.is_empty() {
+                let deny: Vec<String> = self.acl.deny.iter().map(|cidr| cidr.to_string()).collect();
+                try!(s.emit_struct_field("deny", index, |s| deny.encode(s)));
+            }
+            Ok(())
+        })
+    }
+}
+
+/// What a worker knows about each backend: its flows, and whether it is healthy.
+///
+/// Backends out of rotation are only picked when all the backends of a pool are.
+#[derive(Clone, Debug)]
+pub struct Backends(Rc<RefCell<BackendStates>>);
+
+#[derive(Debug)]
+struct BackendStates {
+    states: HashMap<Addr, State>,
+
+    // consecutive connect failures that take a backend out of rotation
+    max_fails: usize,
+
+    // for how long, in ms
+    fail_timeout: u64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // flows connected (or connecting) to the backend
+    flows: usize,
+
+    // consecutive failures of flows to connect
+    fails: usize,
+
+    // out of rotation until then, after max_fails
+    ejected_until: Option<Instant>,
+
+    // outcome of the last active health check (None if not checked)
+    check: Option<Result<(), String>>,
+}
+
+impl State {
+    fn ejected(&self) -> bool {
+        self.ejected_until.map_or(false, |until| until > Instant::now())
+    }
+
+    fn available(&self) -> bool {
+        !self.ejected() && self.check.as_ref().map_or(true, |check| check.is_ok())
+    }
+
+    // nothing worth remembering
+    fn is_default(&self) -> bool {
+        self.flows == 0 && self.fails == 0 && self.ejected_until.is_none() && self.check.is_none()
+    }
+}
+
+impl Backends {
+    pub fn new(max_fails: usize, fail_timeout: u64) -> Backends {
+        Backends(Rc::new(RefCell::new(BackendStates {
+            states: HashMap::new(),
+            max_fails: max_fails,
+            fail_timeout: fail_timeout,
+        })))
+    }
+
+    /// Changes max_fails and fail_timeout (for the next failures).
+    pub fn set_policy(&self, max_fails: usize, fail_timeout: u64) {
+        let mut backends = self.0.borrow_mut();
+        backends.max_fails = max_fails;
+        backends.fail_timeout = fail_timeout;
+    }
+
+    pub fn flows(&self, backend: &Addr) -> usize {
+        self.0.borrow().states.get(backend).map_or(0, |state| state.flows)
+    }
+
+    /// Whether the backend is in rotation, i.e. neither ejected nor failing its health checks.
+    pub fn available(&self, backend: &Addr) -> bool {
+        self.0.borrow().states.get(backend).map_or(true, |state| state.available())
+    }
+
+    pub fn add(&self, backend: Addr) {
+        self.0.borrow_mut().states.entry(backend).or_insert(State::default()).flows += 1;
+    }
+
+    pub fn remove(&self, backend: &Addr) {
+        self.update(backend, |state| state.flows -= 1);
+    }
+
+    /// A flow connected to the backend.
+    pub fn connected(&self, backend: &Addr) {
+        self.update(backend, |state| {
+            if state.ejected_until.take().is_some() {
+                info!("Backend {} is back in rotation", backend);
+            }
+            state.fails = 0;
+        });
+    }
+
+    /// A flow failed to connect to the backend, which is ejected after max_fails in a row.
+    ///
+    /// Once fail_timeout is over, it gets flows again, and is ejected again on the first failure.
+    pub fn failed(&self, backend: &Addr) {
+        let mut backends = self.0.borrow_mut();
+        let (max_fails, fail_timeout) = (backends.max_fails, backends.fail_timeout);
+        let state = backends.states.entry(backend.clone()).or_insert(State::default());
+        state.fails += 1;
+        if state.fails >= max_fails {
+            if !state.ejected() {
+                warn!("Ejecting backend {} for {} ms after {} connect failures in a row", backend, fail_timeout, state.fails);
+            }
+            state.ejected_until = Some(Instant::now() + Duration::from_millis(fail_timeout));
+        }
+    }
+
+    /// Records the outcome of an active health check of the backend.
+    ///
+    /// A passed check also puts an ejected backend back in rotation.
+    pub fn checked(&self, backend: &Addr, result: Result<(), String>) {
+        let mut backends = self.0.borrow_mut();
+        let state = backends.states.entry(backend.clone()).or_insert(State::default());
+        match result {
+            Ok(_) => {
+                if !state.available() {
+                    info!("Backend {} passed its health check, back in rotation", backend);
+                }
+                state.fails = 0;
+                state.ejected_until = None;
+            },
+            Err(ref reason) => {
+                if state.check.as_ref().map_or(true, |check| check.is_ok()) {
+                    warn!("Backend {} failed its health check, out of rotation: {}", backend, reason);
+                }
+            },
+        }
+        state.check = Some(result);
+    }
+
+    /// Forgets the outcome of the health checks of the backends, once nobody checks them anymore.
+    pub fn forget_checks(&self, backends: &[Addr]) {
+        for backend in backends {
+            self.update(backend, |state| state.check = None);
+        }
+    }
+
+    /// What the API shows about the backend.
+    pub fn info(&self, backend: &Addr) -> BackendInfo {
+        let backends = self.0.borrow();
+        let default = State::default();
+        let state = backends.states.get(backend).unwrap_or(&default);
+        BackendInfo {
+            backend: backend.to_string(),
+            healthy: state.available(),
+            check: state.check.as_ref().map(|check| match *check {
+                Ok(_) => "up".to_string(),
+                Err(ref reason) => format!("down: {}", reason),
+            }),
+            fails: state.fails,
+            ejected: state.ejected(),
+            flows: state.flows,
+        }
+    }
+
+    fn update<F>(&self, backend: &Addr, f: F) where F: FnOnce(&mut State) {
+        let mut backends = self.0.borrow_mut();
+        let forget = match backends.states.get_mut(backend) {
+            Some(state) => {
+                f(state);
+                state.is_default()
+            },
+            None => false,
+        };
+        if forget {
+            backends.states.remove(backend);
+        }
+    }
+}
+
+/// A backend of a pool, as configured.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Backend {
+    Addr(Addr),
+    /// Resolved thru DNS, each of its addresses being a backend of its own.
+    Host(String, u16),
+}
+
+impl Backend {
+    /// Reads "ip:port" (with brackets around IPv6 addresses), "host:port" or "unix:/path".
+    pub fn parse(destination: &str) -> Result<Backend, String> {
+        if let Some(addr) = Addr::parse(destination) {
+            return Ok(Backend::Addr(addr));
+        }
+        if destination.starts_with(UNIX_PREFIX) {
+            return Err(format!("Bad destination {}, expected an absolute path after {}", destination, UNIX_PREFIX));
+        }
+
+        let bad = || format!("Bad destination {}, expected \"ip:port\", \"host:port\" or \"unix:/path\"", destination);
+        let (host, port) = match destination.rfind(':') {
+            Some(i) => (&destination[..i], &destination[i + 1..]),
+            None => return Err(bad()),
+        };
+        let port = try!(port.parse::<u16>().map_err(|_| bad()));
+
+        // letters, digits and hyphens (and underscores, seen in the wild) between dots
+        let host = host.trim_right_matches('.').to_lowercase();
+        let valid_label = |label: &str| {
+            !label.is_empty() && label.len() <= 63 && !label.starts_with('-') && !label.ends_with('-')
+                && label.chars().all(|c| (c >= 'a' && c <= 'z') || (c >= '0' && c <= '9') || c == '-' || c == '_')
+        };
+        if host.len() > 253 || !host.split('.').all(valid_label) {
+            return Err(bad());
+        }
+
+        Ok(Backend::Host(host, port))
+    }
+}
+
+/// Outcome of resolving the backends of a pool.
+#[derive(Debug)]
+pub enum Resolution {
+    /// Their addresses, with their weights.
+    Addrs(Vec<(Addr, usize)>),
+    /// Waiting for DNS to resolve this host name.
+    Pending(String),
+    Failed(String),
+}
+
+/// The backends of a route, along with what its strategy remembers between connections.
+///
+/// Every worker has its own pools, so e.g. round-robin goes round per worker.
+/// The strategy picks among the addresses of the backends, so a host name resolving
+/// to several addresses makes for several backends (each with the weight of the host).
+#[derive(Debug)]
+pub struct Pool {
+    destinations: Destinations,
+    // parsed, with their weights (1 unless Strategy::Weighted)
+    backends: Vec<(Backend, usize)>,
+
+    // round-robin (and ties of least-connections): the address to start with next time
+    next: Cell<usize>,
+
+    // weighted: the current weights of the smooth weighted round-robin (as in nginx)
+    current_weights: RefCell<HashMap<Addr, isize>>,
+
+    // consistent-hash: the addresses the ring is made of, and its points (sorted) with their address
+    ring: RefCell<(Vec<Addr>, Vec<(u64, usize)>)>,
+}
+
+impl Pool {
+    /// Parses the backends once and for all, so connections only have to pick one.
+    pub fn new(destinations: Destinations) -> Result<Pool, String> {
+        let mut backends = Vec::new();
+        for (i, backend) in destinations.backends.iter().enumerate() {
+            let weight = destinations.weights.get(i).cloned().unwrap_or(1);
+            backends.push((try!(Backend::parse(backend)), weight));
+        }
+
+        Ok(Pool {
+            destinations: destinations,
+            backends: backends,
+            next: Cell::new(0),
+            current_weights: RefCell::new(HashMap::new()),
+            ring: RefCell::new((Vec::new(), Vec::new())),
+        })
+    }
+
+    pub fn destinations(&self) -> &Destinations {
+        &self.destinations
+    }
+
+    /// The addresses of the backends, resolving the host names among them.
+    ///
+    /// Backends that fail to resolve are left out, unless none of them resolves.
+    pub fn resolve(&self, resolver: &Resolver) -> Resolution {
+        let mut addrs = Vec::new();
+        let mut failure = None;
+
+        for &(ref backend, weight) in self.backends.iter() {
+            match *backend {
+                Backend::Addr(ref addr) => addrs.push((addr.clone(), weight)),
+                Backend::Host(ref host, port) => match resolver.lookup(host) {
+                    Lookup::Found(ips) => {
+                        addrs.extend(ips.into_iter().map(|ip| (Addr::Inet(SocketAddr::new(ip, port)), weight)));
+                    },
+                    Lookup::Pending => return Resolution::Pending(host.clone()),
+                    Lookup::Failed(e) => failure = Some(format!("{}: {}", host, e)),
+                },
+            }
+        }
+
+        match failure {
+            Some(e) if addrs.is_empty() => Resolution::Failed(e),
+            _ => Resolution::Addrs(addrs),
+        }
+    }
+
+    /// Picks the backend of a new connection among the (resolved) addresses,
+    /// followed by the others as alternates (in case connecting fails).
+    ///
+    /// The strategy picks among the backends in rotation (or among all of them, if none is).
+    /// The ones out of rotation come last, they are only tried if all the others fail.
+    pub fn pick(&self, client: Option<IpAddr>, backends: &Backends, addrs: &[(Addr, usize)]) -> Vec<Addr> {
+        let n = addrs.len();
+        if n == 0 {
+            return Vec::new();
+        }
+
+        let mut available: Vec<bool> = addrs.iter().map(|&(ref addr, _)| backends.available(addr)).collect();
+        if !available.iter().any(|a| *a) {
+            available = vec![true; n];
+        }
+
+        let first = match self.destinations.strategy {
+            Strategy::Failover => 0,
+            Strategy::RoundRobin => {
+                (0..n).map(|_| self.advance(n))
+                    .find(|i| available[*i])
+                    .unwrap_or(0)
+            },
+            Strategy::Weighted => self.pick_weighted(addrs, &available),
+            Strategy::LeastConnections => {
+                let start = self.advance(n);
+                (0..n).map(|i| (start + i) % n)
+                    .filter(|i| available[*i])
+                    .min_by_key(|i| backends.flows(&addrs[*i].0))
+                    .unwrap_or(0)
+            },
+            Strategy::Random => {
+                let candidates: Vec<usize> = (0..n).filter(|i| available[*i]).collect();
+                candidates[rand::thread_rng().gen_range(0, candidates.len())]
+            },
+            Strategy::ConsistentHash => 0,
+        };
+
+        let mut order: Vec<usize> = match (self.destinations.strategy, client) {
+            (Strategy::ConsistentHash, Some(client)) => self.ring_order(client, addrs),
+            _ => (0..n).map(|i| (first + i) % n).collect(),
+        };
+
+        // stable, so the alternates keep their order
+        order.sort_by_key(|i| !available[*i]);
+        order.into_iter().map(|i| addrs[i].0.clone()).collect()
+    }
+
+    fn advance(&self, n: usize) -> usize {
+        let next = self.next.get() % n;
+        self.next.set((next + 1) % n);
+        next
+    }
+
+    /// Smooth weighted round-robin among the available addresses.
+    fn pick_weighted(&self, addrs: &[(Addr, usize)], available: &[bool]) -> usize {
+        let mut current_weights = self.current_weights.borrow_mut();
+        // forget the addresses a host name no longer resolves to
+        if current_weights.len() > addrs.len() {
+            current_weights.retain(|addr, _| addrs.iter().any(|&(ref a, _)| a == addr));
+        }
+
+        let mut best: Option<(usize, isize)> = None;
+        let mut total = 0;
+        for (i, &(ref addr, weight)) in addrs.iter().enumerate() {
+            if !available[i] {
+                continue;
+            }
+            let current = current_weights.entry(addr.clone()).or_insert(0);
+            *current += weight as isize;
+            total += weight as isize;
+            if best.map_or(true, |(_, best_weight)| *current > best_weight) {
+                best = Some((i, *current));
+            }
+        }
+
+        match best {
+            Some((i, _)) => {
+                *current_weights.entry(addrs[i].0.clone()).or_insert(0) -= total;
+                i
+            },
+            None => 0,
+        }
+    }
+
+    /// The order in which the addresses come up on the ring after the client,
+    /// which is the order of the alternates.
+    fn ring_order(&self, client: IpAddr, addrs: &[(Addr, usize)]) -> Vec<usize> {
+        let current: Vec<Addr> = addrs.iter().map(|&(ref addr, _)| addr.clone()).collect();
+        let mut ring = self.ring.borrow_mut();
+        if ring.0 != current {
+            let mut points = Vec::new();
+            for (i, addr) in current.iter().enumerate() {
+                for point in 0..RING_POINTS {
+                    points.push((hash(format!("{}-{}", addr, point).as_bytes()), i));
+                }
+            }
+            points.sort();
+            *ring = (current, points);
+        }
+
+        let points = &ring.1;
+        let h = hash(client.to_string().as_bytes());
+        let start = match points.binary_search(&(h, 0)) {
+            Ok(i) | Err(i) => i,
+        };
+        let mut order: Vec<usize> = Vec::new();
+        for j in 0..points.len() {
+            let i = points[(start + j) % points.len()].1;
+            if !order.contains(&i) {
+                order.push(i);
+                if order.len() == addrs.len() {
+                    break;
+                }
+            }
+        }
+        order
+    }
+}
+
+/// The addresses of the backends of the pools (that are resolved already), without duplicates.
+pub fn resolved_addrs(pools: &[Rc<Pool>], resolver: &Resolver) -> Vec<Addr> {
+    let mut result = Vec::new();
+    for pool in pools {
+        if let Resolution::Addrs(addrs) = pool.resolve(resolver) {
+            for (addr, _) in addrs {
+                if !result.contains(&addr) {
+                    result.push(addr);
+                }
+            }
+        }
+    }
+    result
+}
+
+/// FNV-1a, which is the same for every worker (and every run), followed by the finalizer
+/// of MurmurHash3 to spread similar inputs (like the points of a backend) around the ring.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn addr(s: &str) -> Addr {
+        Addr::parse(s).unwrap()
+    }
+
+    fn new_pool(strategy: Strategy, backends: &[&str], weights: Vec<usize>) -> Pool {
+        let backends = backends.iter().map(|backend| backend.to_string()).collect();
+        Pool::new(Destinations::pool(backends, strategy, weights, None, Acl::default()).unwrap()).unwrap()
+    }
+
+    fn addrs(backends: &[&str], weights: &[usize]) -> Vec<(Addr, usize)> {
+        backends.iter().zip(weights.iter()).map(|(backend, weight)| (addr(backend), *weight)).collect()
+    }
+
+    // a worker knowing nothing about the backends yet
+    fn fresh() -> Backends {
+        Backends::new(1, 60000)
+    }
+
+    fn client(i: usize) -> Option<IpAddr> {
+        Some(IpAddr::V4(Ipv4Addr::new(192, 0, (i / 256) as u8, (i % 256) as u8)))
+    }
+
+    const A: &'static str = "10.0.0.1:80";
+    const B: &'static str = "10.0.0.2:80";
+    const C: &'static str = "10.0.0.3:80";
+
+    #[test]
+    fn round_robin() {
+        let pool = new_pool(Strategy::RoundRobin, &[A, B, C], Vec::new());
+        let addrs = addrs(&[A, B, C], &[1, 1, 1]);
+        let backends = fresh();
+
+        assert_eq!(pool.pick(None, &backends, &addrs), vec![addr(A), addr(B), addr(C)]);
+        assert_eq!(pool.pick(None, &backends, &addrs), vec![addr(B), addr(C), addr(A)]);
+        assert_eq!(pool.pick(None, &backends, &addrs), vec![addr(C), addr(A), addr(B)]);
+        assert_eq!(pool.pick(None, &backends, &addrs), vec![addr(A), addr(B), addr(C)]);
+        assert_eq!(pool.pick(None, &backends, &[]), Vec::<Addr>::new());
+    }
+
+    #[test]
+    fn smooth_weighted_round_robin() {
+        let pool = new_pool(Strategy::Weighted, &[A, B, C], vec![5, 1, 1]);
+        let addrs = addrs(&[A, B, C], &[5, 1, 1]);
+        let backends = fresh();
+
+        // like nginx, spread out rather than a,a,a,a,a,b,c
+        let picked: Vec<Addr> = (0..14).map(|_| pool.pick(None, &backends, &addrs)[0].clone()).collect();
+        let expected: Vec<Addr> = [A, A, B, A, C, A, A, A, A, B, A, C, A, A].iter().map(|s| addr(s)).collect();
+        assert_eq!(picked, expected);
+    }
+
+    #[test]
+    fn weighted_skips_unavailable() {
+        let pool = new_pool(Strategy::Weighted, &[A, B, C], vec![5, 1, 1]);
+        let addrs = addrs(&[A, B, C], &[5, 1, 1]);
+        let backends = fresh();
+        backends.failed(&addr(A));
+
+        let picked: Vec<Addr> = (0..4).map(|_| pool.pick(None, &backends, &addrs)[0].clone()).collect();
+        assert_eq!(picked, vec![addr(B), addr(C), addr(B), addr(C)]);
+    }
+
+    #[test]
+    fn least_connections() {
+        let pool = new_pool(Strategy::LeastConnections, &[A, B, C], Vec::new());
+        let addrs = addrs(&[A, B, C], &[1, 1, 1]);
+        let backends = fresh();
+
+        backends.add(addr(A));
+        backends.add(addr(A));
+        backends.add(addr(B));
+        assert_eq!(pool.pick(None, &backends, &addrs)[0], addr(C));
+
+        // the fewest flows win, wherever the rotation starts
+        backends.add(addr(C));
+        backends.add(addr(C));
+        let picked: Vec<Addr> = (0..3).map(|_| pool.pick(None, &backends, &addrs)[0].clone()).collect();
+        assert_eq!(picked, vec![addr(B), addr(B), addr(B)]);
+
+        // ties go round like round-robin, which is at its 5th pick
+        backends.add(addr(B));
+        let picked: Vec<Addr> = (0..3).map(|_| pool.pick(None, &backends, &addrs)[0].clone()).collect();
+        assert_eq!(picked, vec![addr(B), addr(C), addr(A)]);
+    }
+
+    #[test]
+    fn consistent_hash_is_sticky() {
+        let pool = new_pool(Strategy::ConsistentHash, &[A, B, C], Vec::new());
+        let addrs = addrs(&[A, B, C], &[1, 1, 1]);
+        let backends = fresh();
+
+        let mut counts = HashMap::new();
+        for i in 0..300 {
+            let order = pool.pick(client(i), &backends, &addrs);
+            assert_eq!(order.len(), 3);
+            for _ in 0..3 {
+                assert_eq!(pool.pick(client(i), &backends, &addrs), order);
+            }
+            // and for any worker, i.e. any pool
+            let other = new_pool(Strategy::ConsistentHash, &[A, B, C], Vec::new());
+            assert_eq!(other.pick(client(i), &backends, &addrs), order);
+            *counts.entry(order[0].clone()).or_insert(0) += 1;
+        }
+
+        // roughly evenly
+        for backend in [A, B, C].iter() {
+            assert!(counts[&addr(backend)] > 50, "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn consistent_hash_moves_only_the_clients_of_a_removed_backend() {
+        let pool = new_pool(Strategy::ConsistentHash, &[A, B, C], Vec::new());
+        let all = addrs(&[A, B, C], &[1, 1, 1]);
+        let without_b = addrs(&[A, C], &[1, 1]);
+        let backends = fresh();
+
+        let mut moved = 0;
+        for i in 0..300 {
+            let before = pool.pick(client(i), &backends, &all);
+            let after = pool.pick(client(i), &backends, &without_b);
+            if before[0] == addr(B) {
+                // to the next backend on the ring, its first alternate
+                assert_eq!(after[0], before[1]);
+                moved += 1;
+            } else {
+                assert_eq!(after[0], before[0]);
+            }
+        }
+        assert!(moved > 0);
+    }
+
+    #[test]
+    fn unavailable_backends_come_last() {
+        let backends = fresh();
+        backends.failed(&addr(B));
+        assert!(!backends.available(&addr(B)));
+
+        for strategy in [Strategy::Failover, Strategy::RoundRobin, Strategy::LeastConnections, Strategy::Random,
+                         Strategy::ConsistentHash].iter() {
+            let pool = new_pool(*strategy, &[A, B, C], Vec::new());
+            let addrs = addrs(&[A, B, C], &[1, 1, 1]);
+            for i in 0..20 {
+                let order = pool.pick(client(i), &backends, &addrs);
+                assert_eq!(order.len(), 3);
+                assert_eq!(order[2], addr(B), "{:?}", strategy);
+            }
+        }
+
+        // failover keeps the order of the others
+        let pool = new_pool(Strategy::Failover, &[B, A, C], Vec::new());
+        assert_eq!(pool.pick(None, &backends, &addrs(&[B, A, C], &[1, 1, 1])), vec![addr(A), addr(C), addr(B)]);
+    }
+
+    #[test]
+    fn all_unavailable() {
+        let backends = fresh();
+        backends.failed(&addr(A));
+        backends.failed(&addr(B));
+
+        // as good as all available
+        let pool = new_pool(Strategy::RoundRobin, &[A, B], Vec::new());
+        let addrs = addrs(&[A, B], &[1, 1]);
+        assert_eq!(pool.pick(None, &backends, &addrs), vec![addr(A), addr(B)]);
+        assert_eq!(pool.pick(None, &backends, &addrs), vec![addr(B), addr(A)]);
+    }
+}
@@ -0,0 +1,119 @@
+use proxy;
+
+/// Default for how long to wait for a client to speak first, in ms.
+pub const WAIT_TIMEOUT: u64 = 2000;
+
+/// The protocols told apart by their first bytes.
+pub const PROTOCOLS: [&'static str; 4] = ["tls", "ssh", "http", "proxy"];
+
+// how each protocol starts (any of its signatures will do)
+const SIGNATURES: [(&'static str, &'static [u8]); 14] = [
+    // a handshake record of SSL 3.0 or any TLS version
+    ("tls", b"\x16\x03"),
+    // the version banner of the client, e.g. SSH-2.0-OpenSSH_9.2p1
+    ("ssh", b"SSH-"),
+    ("http", b"GET "),
+    ("http", b"HEAD "),
+    ("http", b"POST "),
+    ("http", b"PUT "),
+    ("http", b"DELETE "),
+    ("http", b"OPTIONS "),
+    ("http", b"PATCH "),
+    ("http", b"CONNECT "),
+    ("http", b"TRACE "),
+    // the connection preface of HTTP/2 with prior knowledge
+    ("http", b"PRI * HTTP/2.0"),
+    // PROXY protocol headers, version 1 and 2
+    ("proxy", proxy::V1_SIGNATURE),
+    ("proxy", proxy::V2_SIGNATURE),
+];
+
+/// Outcome of sniffing the first bytes of an inbound connection.
+#[derive(Debug)]
+pub enum Detected {
+    /// What the client sent so far is the start of a signature.
+    NeedMore,
+    /// None of the known protocols.
+    Unknown,
+    Protocol(&'static str),
+}
+
+/// Tells the protocol of an inbound connection by its first bytes.
+pub fn detect(buf: &[u8]) -> Detected {
+    let mut need_more = false;
+
+    for &(protocol, signature) in SIGNATURES.iter() {
+        if buf.starts_with(signature) {
+            return Detected::Protocol(protocol);
+        }
+        if signature.starts_with(buf) {
+            need_more = true;
+        }
+    }
+
+    if need_more {
+        Detected::NeedMore
+    } else {
+        Detected::Unknown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proxy;
+
+    fn protocol(buf: &[u8]) -> &'static str {
+        match detect(buf) {
+            Detected::Protocol(protocol) => protocol,
+            detected => panic!("expected a protocol for {:?}, got {:?}", buf, detected),
+        }
+    }
+
+    fn need_more(buf: &[u8]) {
+        match detect(buf) {
+            Detected::NeedMore => (),
+            detected => panic!("expected NeedMore for {:?}, got {:?}", buf, detected),
+        }
+    }
+
+    #[test]
+    fn partial_signatures() {
+        need_more(b"");
+        need_more(b"\x16");
+        need_more(b"SS");
+        need_more(b"GE");
+        need_more(b"PRI * HTTP/2");
+        need_more(b"PROXY");
+        need_more(b"\r\n\r\n\x00");
+        // POST, PUT, PATCH, PRI or PROXY
+        need_more(b"P");
+    }
+
+    #[test]
+    fn signatures() {
+        assert_eq!(protocol(b"\x16\x03\x01\x02\x00\x01"), "tls");
+        assert_eq!(protocol(b"\x16\x03\x00"), "tls");
+        assert_eq!(protocol(b"SSH-2.0-OpenSSH_9.2p1\r\n"), "ssh");
+        assert_eq!(protocol(b"GET / HTTP/1.1\r\n"), "http");
+        assert_eq!(protocol(b"OPTIONS * HTTP/1.1\r\n"), "http");
+        assert_eq!(protocol(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"), "http");
+        assert_eq!(protocol(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n"), "proxy");
+        assert_eq!(protocol(&[proxy::V2_SIGNATURE, b"\x21\x11\x00\x0c"].concat()), "proxy");
+
+        for &(name, signature) in SIGNATURES.iter() {
+            assert_eq!(protocol(signature), name);
+            assert!(PROTOCOLS.contains(&name));
+        }
+    }
+
+    #[test]
+    fn unknown() {
+        for buf in [&b"\x16\x02"[..], b"SSH2", b"get / HTTP/1.1\r\n", b"GETS", b"\r\n\r\n\x01", b"\x00", b"hello"].iter() {
+            match detect(buf) {
+                Detected::Unknown => (),
+                detected => panic!("expected Unknown for {:?}, got {:?}", buf, detected),
+            }
+        }
+    }
+}
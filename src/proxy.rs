@@ -0,0 +1,534 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// How PROXY protocol v1 headers start.
+pub const V1_SIGNATURE: &'static [u8] = b"PROXY ";
+
+/// How PROXY protocol v2 headers start.
+pub const V2_SIGNATURE: &'static [u8] = b"\r\n\r\n\x00\r\nQUIT\n";
+
+// the longest v1 header, "PROXY UNKNOWN" followed by whatever up to the CRLF
+const V1_MAX_LEN: usize = 107;
+
+// signature, version and command, address family and transport protocol, length of the rest
+const V2_HEADER_LEN: usize = 16;
+
+// version 2 and the LOCAL or PROXY command
+const V2_LOCAL: u8 = 0x20;
+const V2_PROXY: u8 = 0x21;
+
+// address family and transport protocol
+const V2_UNSPEC: u8 = 0x00;
+const V2_TCP4: u8 = 0x11;
+const V2_UDP4: u8 = 0x12;
+const V2_TCP6: u8 = 0x21;
+const V2_UDP6: u8 = 0x22;
+
+// types of TLVs (type-length-value fields following the addresses)
+const PP2_TYPE_ALPN: u8 = 0x01;
+const PP2_TYPE_AUTHORITY: u8 = 0x02;
+
+/// Version of the PROXY protocol headers sent to the backends of a route.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Version {
+    /// The human-readable header, e.g. "PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n".
+    V1,
+    /// The binary header, which also tells the SNI and ALPN of TLS clients.
+    V2,
+}
+
+impl Version {
+    pub fn parse(name: &str) -> Result<Version, String> {
+        match name {
+            "v1" => Ok(Version::V1),
+            "v2" => Ok(Version::V2),
+            _ => Err(format!("Bad PROXY protocol version {}, expected \"v1\" or \"v2\"", name)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Version::V1 => "v1",
+            Version::V2 => "v2",
+        }
+    }
+}
+
+/// A PROXY protocol header received from a client (i.e. a load balancer in front of tnexus).
+#[derive(Debug)]
+pub struct Header {
+    // where the bytes of the client itself start
+    pub len: usize,
+    // of the original connection, None if the header does not tell
+    // (e.g. for the health checks of the load balancer)
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+}
+
+/// Outcome of parsing the first bytes of an inbound connection as a PROXY protocol header.
+#[derive(Debug)]
+pub enum ParseResult {
+    /// Not enough bytes to decide yet.
+    NeedMore,
+    /// Not a PROXY protocol header, or one that does not make sense.
+    Malformed(String),
+    Header(Header),
+}
+
+/// Parse the PROXY protocol header (v1 or v2) at the start of the buffer.
+pub fn parse(buf: &[u8]) -> ParseResult {
+    if starts_with(buf, V1_SIGNATURE) {
+        if buf.len() < V1_SIGNATURE.len() {
+            ParseResult::NeedMore
+        } else {
+            parse_v1(buf)
+        }
+    } else if starts_with(buf, V2_SIGNATURE) {
+        if buf.len() < V2_HEADER_LEN {
+            ParseResult::NeedMore
+        } else {
+            parse_v2(buf)
+        }
+    } else {
+        ParseResult::Malformed("no PROXY protocol header".to_string())
+    }
+}
+
+/// Whether the buffer starts with the signature (or with the part of it received so far).
+fn starts_with(buf: &[u8], signature: &[u8]) -> bool {
+    let len = if buf.len() < signature.len() { buf.len() } else { signature.len() };
+    buf[..len] == signature[..len]
+}
+
+/// "PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n" or "PROXY UNKNOWN ...\r\n"
+fn parse_v1(buf: &[u8]) -> ParseResult {
+    let end = match buf.windows(2).take(V1_MAX_LEN - 1).position(|crlf| crlf == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() < V1_MAX_LEN => return ParseResult::NeedMore,
+        None => return ParseResult::Malformed(format!("no CRLF in the first {} bytes of a v1 header", V1_MAX_LEN)),
+    };
+    let line = match ::std::str::from_utf8(&buf[V1_SIGNATURE.len()..end]) {
+        Ok(line) => line,
+        Err(_) => return ParseResult::Malformed("v1 header is not ASCII".to_string()),
+    };
+
+    let fields: Vec<&str> = line.split(' ').collect();
+    let addrs = match fields[0] {
+        "UNKNOWN" => None,
+        "TCP4" | "TCP6" if fields.len() == 5 => {
+            let ips = (fields[1].parse::<IpAddr>(), fields[2].parse::<IpAddr>());
+            let ports = (fields[3].parse::<u16>(), fields[4].parse::<u16>());
+            match (ips, ports) {
+                ((Ok(source), Ok(destination)), (Ok(source_port), Ok(destination_port)))
+                    if source.is_ipv4() == (fields[0] == "TCP4") && destination.is_ipv4() == (fields[0] == "TCP4") =>
+                    Some((SocketAddr::new(source, source_port), SocketAddr::new(destination, destination_port))),
+                _ => return ParseResult::Malformed(format!("bad addresses in v1 header {:?}", line)),
+            }
+        },
+        _ => return ParseResult::Malformed(format!("bad v1 header {:?}", line)),
+    };
+
+    ParseResult::Header(Header {
+        len: end + 2,
+        source: addrs.map(|(source, _)| source),
+        destination: addrs.map(|(_, destination)| destination),
+    })
+}
+
+/// The signature, then version and command, address family and protocol, and the length of the rest:
+/// the addresses, and TLVs (ignored).
+fn parse_v2(buf: &[u8]) -> ParseResult {
+    let len = V2_HEADER_LEN + ((buf[14] as usize) << 8 | buf[15] as usize);
+    if buf.len() < len {
+        return ParseResult::NeedMore;
+    }
+    let body = &buf[V2_HEADER_LEN..len];
+
+    let addrs = match (buf[12], buf[13]) {
+        // e.g. the health checks of the load balancer
+        (V2_LOCAL, _) => None,
+        (V2_PROXY, V2_TCP4) | (V2_PROXY, V2_UDP4) if body.len() >= 12 => {
+            let ip = |at: usize| IpAddr::V4(Ipv4Addr::new(body[at], body[at + 1], body[at + 2], body[at + 3]));
+            Some((SocketAddr::new(ip(0), port(body, 8)), SocketAddr::new(ip(4), port(body, 10))))
+        },
+        (V2_PROXY, V2_TCP6) | (V2_PROXY, V2_UDP6) if body.len() >= 36 => {
+            let ip = |at: usize| {
+                let mut segments = [0u16; 8];
+                for (i, segment) in segments.iter_mut().enumerate() {
+                    *segment = port(body, at + 2 * i);
+                }
+                IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                                         segments[4], segments[5], segments[6], segments[7]))
+            };
+            Some((SocketAddr::new(ip(0), port(body, 32)), SocketAddr::new(ip(16), port(body, 34))))
+        },
+        // Unix domain sockets and unknown families
+        (V2_PROXY, family) if family == V2_UNSPEC || family & 0xf0 > 0x20 => None,
+        (command, family) => return ParseResult::Malformed(format!("bad v2 header, command {:#x} family {:#x} length {}",
+                                                                   command, family, body.len())),
+    };
+
+    ParseResult::Header(Header {
+        len: len,
+        source: addrs.map(|(source, _)| source),
+        destination: addrs.map(|(_, destination)| destination),
+    })
+}
+
+fn port(buf: &[u8], at: usize) -> u16 {
+    (buf[at] as u16) << 8 | buf[at + 1] as u16
+}
+
+/// The header telling a backend where a connection comes from (`source`), and where it went to
+/// (`destination`, i.e. the endpoint of tnexus that accepted it).
+///
+/// If either is unknown (e.g. for clients of a Unix domain socket), the header says so, and the
+/// backend is expected to use the address of the connection from tnexus instead.
+/// The server name and the ALPN protocol (the one the client prefers) are only sent by v2.
+pub fn header(version: Version, source: Option<SocketAddr>, destination: Option<SocketAddr>,
+              server_name: Option<&str>, alpn: Option<&str>) -> Vec<u8> {
+    let addrs = match (source, destination) {
+        (Some(source), Some(destination)) => Some(same_family(source, destination)),
+        _ => None,
+    };
+
+    match version {
+        Version::V1 => match addrs {
+            Some((source, destination)) => {
+                let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!("PROXY {} {} {} {} {}\r\n", family, source.ip(), destination.ip(), source.port(), destination.port())
+                    .into_bytes()
+            },
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        Version::V2 => {
+            let mut body = Vec::new();
+            let family = match addrs {
+                Some((SocketAddr::V4(source), SocketAddr::V4(destination))) => {
+                    body.extend_from_slice(&source.ip().octets());
+                    body.extend_from_slice(&destination.ip().octets());
+                    body.extend_from_slice(&u16_bytes(source.port()));
+                    body.extend_from_slice(&u16_bytes(destination.port()));
+                    V2_TCP4
+                },
+                Some((source, destination)) => {
+                    for addr in [source, destination].iter() {
+                        match addr.ip() {
+                            IpAddr::V6(ip) => body.extend_from_slice(&ip.octets()),
+                            IpAddr::V4(ip) => body.extend_from_slice(&ip.to_ipv6_mapped().octets()),
+                        }
+                    }
+                    body.extend_from_slice(&u16_bytes(source.port()));
+                    body.extend_from_slice(&u16_bytes(destination.port()));
+                    V2_TCP6
+                },
+                None => V2_UNSPEC,
+            };
+
+            if let Some(alpn) = alpn {
+                push_tlv(&mut body, PP2_TYPE_ALPN, alpn.as_bytes());
+            }
+            if let Some(server_name) = server_name {
+                push_tlv(&mut body, PP2_TYPE_AUTHORITY, server_name.as_bytes());
+            }
+
+            let mut header = V2_SIGNATURE.to_vec();
+            header.push(V2_PROXY);
+            header.push(family);
+            header.extend_from_slice(&u16_bytes(body.len() as u16));
+            header.extend_from_slice(&body);
+            header
+        },
+    }
+}
+
+/// Both addresses as IPv6 (IPv4-mapped) addresses if only one of them is.
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let to_ipv6 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+
+    if source.is_ipv4() == destination.is_ipv4() {
+        (source, destination)
+    } else {
+        (to_ipv6(source), to_ipv6(destination))
+    }
+}
+
+fn push_tlv(buf: &mut Vec<u8>, tlv_type: u8, value: &[u8]) {
+    buf.push(tlv_type);
+    buf.extend_from_slice(&u16_bytes(value.len() as u16));
+    buf.extend_from_slice(value);
+}
+
+fn u16_bytes(n: u16) -> [u8; 2] {
+    [(n >> 8) as u8, n as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn parsed(buf: &[u8]) -> Header {
+        match parse(buf) {
+            ParseResult::Header(header) => header,
+            result => panic!("expected a header, got {:?}", result),
+        }
+    }
+
+    fn malformed(buf: &[u8]) -> String {
+        match parse(buf) {
+            ParseResult::Malformed(reason) => reason,
+            result => panic!("expected a malformed header, got {:?}", result),
+        }
+    }
+
+    fn need_more(buf: &[u8]) {
+        match parse(buf) {
+            ParseResult::NeedMore => (),
+            result => panic!("expected NeedMore, got {:?}", result),
+        }
+    }
+
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        [V2_SIGNATURE, &[command, family, (body.len() >> 8) as u8, body.len() as u8][..], body].concat()
+    }
+
+    #[test]
+    fn v1() {
+        let received = parsed(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n");
+        assert_eq!(received.len, 45);
+        assert_eq!(received.source, Some(addr("192.0.2.1:56324")));
+        assert_eq!(received.destination, Some(addr("198.51.100.1:443")));
+
+        let received = parsed(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n\x16\x03\x01");
+        assert_eq!(received.len, 46);
+        assert_eq!(received.source, Some(addr("[2001:db8::1]:56324")));
+        assert_eq!(received.destination, Some(addr("[2001:db8::2]:443")));
+    }
+
+    #[test]
+    fn v1_unknown() {
+        let received = parsed(b"PROXY UNKNOWN\r\n");
+        assert_eq!(received.len, 15);
+        assert_eq!(received.source, None);
+        assert_eq!(received.destination, None);
+
+        // whatever follows UNKNOWN is ignored
+        let received = parsed(b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n");
+        assert_eq!(received.len, 59);
+        assert_eq!(received.source, None);
+    }
+
+    #[test]
+    fn v1_incomplete() {
+        need_more(b"PRO");
+        need_more(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r");
+        need_more(&[&b"PROXY UNKNOWN "[..], &[b'x'; 92][..]].concat());
+    }
+
+    #[test]
+    fn v1_too_long() {
+        let line = [&b"PROXY UNKNOWN "[..], &[b'x'; 91][..], b"\r\n"].concat();
+        assert_eq!(line.len(), V1_MAX_LEN);
+        assert_eq!(parsed(&line).len, V1_MAX_LEN);
+
+        let line = [&b"PROXY UNKNOWN "[..], &[b'x'; 92][..], b"\r\n"].concat();
+        assert_eq!(malformed(&line), "no CRLF in the first 107 bytes of a v1 header");
+        assert_eq!(malformed(&line[..V1_MAX_LEN]), "no CRLF in the first 107 bytes of a v1 header");
+    }
+
+    #[test]
+    fn v1_malformed() {
+        malformed(b"PROXY TCP4 2001:db8::1 198.51.100.1 56324 443\r\n");
+        malformed(b"PROXY TCP4 192.0.2.1 2001:db8::2 56324 443\r\n");
+        malformed(b"PROXY TCP6 192.0.2.1 198.51.100.1 56324 443\r\n");
+        malformed(b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n");
+        malformed(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n");
+        malformed(b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n");
+        malformed(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\n\r\n");
+        malformed(b"PROXY \xff\r\n");
+        assert_eq!(malformed(b"GET / HTTP/1.1\r\n"), "no PROXY protocol header");
+    }
+
+    #[test]
+    fn v2_local() {
+        let buf = v2(V2_LOCAL, V2_UNSPEC, &[]);
+        let received = parsed(&buf);
+        assert_eq!(received.len, 16);
+        assert_eq!(received.source, None);
+        assert_eq!(received.destination, None);
+
+        // the addresses of a LOCAL header are not the client's
+        let buf = v2(V2_LOCAL, V2_TCP4, &[192, 0, 2, 1, 198, 51, 100, 1, 0, 1, 0, 2]);
+        let received = parsed(&buf);
+        assert_eq!(received.len, 28);
+        assert_eq!(received.source, None);
+    }
+
+    #[test]
+    fn v2_tcp4() {
+        // followed by a NOOP TLV
+        let buf = v2(V2_PROXY, V2_TCP4, &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb, 0x04, 0, 2, 0, 0]);
+        let received = parsed(&[&buf[..], b"GET / HTTP/1.1\r\n"].concat());
+        assert_eq!(received.len, 33);
+        assert_eq!(received.source, Some(addr("192.0.2.1:56324")));
+        assert_eq!(received.destination, Some(addr("198.51.100.1:443")));
+    }
+
+    #[test]
+    fn v2_tcp6() {
+        let mut body = vec![0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        body.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        body.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        body.extend_from_slice(&[PP2_TYPE_AUTHORITY, 0, 11]);
+        body.extend_from_slice(b"example.com");
+        let buf = v2(V2_PROXY, V2_TCP6, &body);
+        let received = parsed(&buf);
+        assert_eq!(received.len, 16 + 36 + 14);
+        assert_eq!(received.source, Some(addr("[2001:db8::1]:56324")));
+        assert_eq!(received.destination, Some(addr("[2001:db8::2]:443")));
+    }
+
+    #[test]
+    fn v2_unix() {
+        // AF_UNIX, SOCK_STREAM: two paths of 108 bytes
+        let buf = v2(V2_PROXY, 0x31, &[0; 216]);
+        let received = parsed(&buf);
+        assert_eq!(received.len, 232);
+        assert_eq!(received.source, None);
+        assert_eq!(received.destination, None);
+    }
+
+    #[test]
+    fn v2_truncated() {
+        let buf = v2(V2_PROXY, V2_TCP4, &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        need_more(&buf[..5]);
+        need_more(&buf[..15]);
+        need_more(&buf[..27]);
+        assert_eq!(parsed(&buf).len, 28);
+
+        // the length leaves no room for the addresses
+        malformed(&v2(V2_PROXY, V2_TCP4, &[192, 0, 2, 1, 198, 51, 100, 1]));
+        malformed(&v2(V2_PROXY, V2_TCP6, &[0; 35]));
+    }
+
+    #[test]
+    fn v2_bad_command() {
+        malformed(&v2(0x22, V2_TCP4, &[0; 12]));
+        // version 1
+        malformed(&v2(0x11, V2_TCP4, &[0; 12]));
+        malformed(&v2(0x01, V2_UNSPEC, &[]));
+    }
+
+    #[test]
+    fn v1_round_trip() {
+        let buf = header(Version::V1, Some(addr("192.0.2.1:56324")), Some(addr("198.51.100.1:443")), Some("example.com"), Some("h2"));
+        assert_eq!(buf, b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n".to_vec());
+        let received = parsed(&buf);
+        assert_eq!(received.len, buf.len());
+        assert_eq!(received.source, Some(addr("192.0.2.1:56324")));
+        assert_eq!(received.destination, Some(addr("198.51.100.1:443")));
+
+        let buf = header(Version::V1, Some(addr("[2001:db8::1]:56324")), Some(addr("[2001:db8::2]:443")), None, None);
+        assert_eq!(buf, b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n".to_vec());
+        let received = parsed(&buf);
+        assert_eq!(received.source, Some(addr("[2001:db8::1]:56324")));
+        assert_eq!(received.destination, Some(addr("[2001:db8::2]:443")));
+
+        let buf = header(Version::V1, None, Some(addr("198.51.100.1:443")), None, None);
+        assert_eq!(buf, b"PROXY UNKNOWN\r\n".to_vec());
+        let received = parsed(&buf);
+        assert_eq!(received.len, buf.len());
+        assert_eq!(received.source, None);
+        assert_eq!(received.destination, None);
+    }
+
+    #[test]
+    fn v1_mixed_families() {
+        // an IPv4 client of a dual-stack endpoint
+        let buf = header(Version::V1, Some(addr("192.0.2.1:56324")), Some(addr("[2001:db8::2]:443")), None, None);
+        assert_eq!(buf, b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 56324 443\r\n".to_vec());
+        let received = parsed(&buf);
+        assert_eq!(received.source, Some(addr("[::ffff:192.0.2.1]:56324")));
+        assert_eq!(received.destination, Some(addr("[2001:db8::2]:443")));
+    }
+
+    #[test]
+    fn v2_tcp4_round_trip() {
+        let buf = header(Version::V2, Some(addr("192.0.2.1:56324")), Some(addr("198.51.100.1:443")), None, None);
+        assert_eq!(&buf[..12], V2_SIGNATURE);
+        assert_eq!(buf[12], V2_PROXY);
+        assert_eq!(buf[13], V2_TCP4);
+        assert_eq!(&buf[14..16], &[0, 12]);
+        assert_eq!(&buf[16..], &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+
+        let received = parsed(&buf);
+        assert_eq!(received.len, buf.len());
+        assert_eq!(received.source, Some(addr("192.0.2.1:56324")));
+        assert_eq!(received.destination, Some(addr("198.51.100.1:443")));
+    }
+
+    #[test]
+    fn v2_tcp6_round_trip() {
+        let buf = header(Version::V2, Some(addr("[2001:db8::1]:56324")), Some(addr("[2001:db8::2]:443")), None, None);
+        assert_eq!(buf[13], V2_TCP6);
+        assert_eq!(&buf[14..16], &[0, 36]);
+        assert_eq!(buf.len(), 16 + 36);
+
+        let received = parsed(&buf);
+        assert_eq!(received.len, buf.len());
+        assert_eq!(received.source, Some(addr("[2001:db8::1]:56324")));
+        assert_eq!(received.destination, Some(addr("[2001:db8::2]:443")));
+    }
+
+    #[test]
+    fn v2_mixed_families() {
+        let buf = header(Version::V2, Some(addr("[2001:db8::1]:56324")), Some(addr("198.51.100.1:443")), None, None);
+        assert_eq!(buf[13], V2_TCP6);
+        assert_eq!(&buf[32..48], &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 198, 51, 100, 1]);
+
+        let received = parsed(&buf);
+        assert_eq!(received.source, Some(addr("[2001:db8::1]:56324")));
+        assert_eq!(received.destination, Some(addr("[::ffff:198.51.100.1]:443")));
+    }
+
+    #[test]
+    fn v2_tlvs() {
+        let buf = header(Version::V2, Some(addr("192.0.2.1:56324")), Some(addr("198.51.100.1:443")),
+                         Some("example.com"), Some("h2"));
+        let tlvs = [&[PP2_TYPE_ALPN, 0, 2][..], b"h2", &[PP2_TYPE_AUTHORITY, 0, 11][..], b"example.com"].concat();
+        assert_eq!(&buf[14..16], &[0, (12 + tlvs.len()) as u8]);
+        assert_eq!(&buf[28..], &tlvs[..]);
+
+        // TLVs are skipped
+        let received = parsed(&buf);
+        assert_eq!(received.len, buf.len());
+        assert_eq!(received.source, Some(addr("192.0.2.1:56324")));
+
+        // and sent for unknown addresses too
+        let buf = header(Version::V2, None, None, Some("example.com"), None);
+        assert_eq!(buf[12], V2_PROXY);
+        assert_eq!(buf[13], V2_UNSPEC);
+        assert_eq!(&buf[14..16], &[0, 14]);
+        assert_eq!(&buf[16..], &[&[PP2_TYPE_AUTHORITY, 0, 11][..], b"example.com"].concat()[..]);
+        let received = parsed(&buf);
+        assert_eq!(received.len, buf.len());
+        assert_eq!(received.source, None);
+        assert_eq!(received.destination, None);
+    }
+
+    #[test]
+    fn same_families() {
+        let (v4, v6) = (addr("192.0.2.1:1"), addr("[2001:db8::2]:2"));
+        assert_eq!(same_family(v4, v4), (v4, v4));
+        assert_eq!(same_family(v6, v6), (v6, v6));
+        assert_eq!(same_family(v4, v6), (addr("[::ffff:192.0.2.1]:1"), v6));
+        assert_eq!(same_family(v6, v4), (v6, addr("[::ffff:192.0.2.1]:1")));
+    }
+}
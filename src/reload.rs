@@ -0,0 +1,280 @@
+use std::thread;
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, Sender, Receiver};
+
+use mio::Sender as MioSender;
+use nix::sys::signal::{SigSet, SIGHUP};
+
+use api::{ApiMsg, RunningApi, API_SNI};
+use config;
+use config::{Config, Endpoint, Overload};
+use listener;
+use listener::Acceptor;
+use pool::Destinations;
+
+/// Messages for the main thread, which owns the running configuration.
+pub enum Control {
+    /// Re-read the config file, optionally reporting the outcome to the requester.
+    Reload(Option<Sender<Result<(), String>>>),
+    /// A worker event loop is done.
+    WorkerDone,
+}
+
+/// What a worker needs to apply a new configuration.
+pub struct Reload {
+    // of this worker, and of the config
+    pub max_connections: usize,
+    pub configured_max_connections: usize,
+    pub overload: Overload,
+    pub max_fails: usize,
+    pub fail_timeout: u64,
+    pub dns_ttl: u64,
+    // every endpoint of the new config, with a freshly bound server socket
+    // if the endpoint is new or its address changed (None keeps the current one)
+    pub endpoints: Vec<(Endpoint, Option<Acceptor>)>,
+}
+
+/// Lets the main thread know when a worker exits (even if it panics).
+pub struct WorkerGuard(pub Sender<Control>);
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        let _ = self.0.send(Control::WorkerDone);
+    }
+}
+
+/// Turns SIGHUP into `Control::Reload`.
+///
+/// Must be called before any other thread is started, so that they all inherit the blocked SIGHUP
+/// and the signal is only ever picked up here.
+pub fn watch_sighup(control: Sender<Control>) {
+    let mut signals = SigSet::empty();
+    signals.add(SIGHUP).ok().expect("Could not setup SIGHUP handling");
+    signals.thread_block().ok().expect("Could not block SIGHUP");
+
+    thread::Builder::new().name("sighup".to_string()).spawn(move || {
+        loop {
+            match signals.wait() {
+                Ok(_) => {
+                    info!("Received SIGHUP");
+                    if control.send(Control::Reload(None)).is_err() {
+                        return;
+                    }
+                },
+                Err(e) => {
+                    error!("Failed waiting for SIGHUP, {:?}", e);
+                    return;
+                },
+            }
+        }
+    }).ok().expect("Could not start SIGHUP thread");
+}
+
+/// Owns the running configuration and applies new ones to all workers.
+pub struct Supervisor {
+    config: Config,
+    workers: Vec<MioSender<ApiMsg>>,
+    // running APIs by the name of their [[listen]] section
+    apis: BTreeMap<String, RunningApi>,
+    control: Sender<Control>,
+}
+
+impl Supervisor {
+    pub fn new(config: Config, workers: Vec<MioSender<ApiMsg>>, apis: Vec<RunningApi>,
+               control: Sender<Control>) -> Supervisor {
+        Supervisor {
+            config: config,
+            workers: workers,
+            apis: apis.into_iter().map(|api| (api.listener().to_string(), api)).collect(),
+            control: control,
+        }
+    }
+
+    /// Handle control messages until all workers are done.
+    pub fn run(&mut self, control: Receiver<Control>) {
+        let mut running = self.workers.len();
+
+        while running > 0 {
+            match control.recv() {
+                Ok(Control::Reload(reply)) => {
+                    let result = self.reload();
+                    match result {
+                        Ok(_) => info!("Reloaded configuration"),
+                        Err(ref e) => error!("Rejected configuration reload: {}", e),
+                    }
+                    if let Some(reply) = reply {
+                        let _ = reply.send(result);
+                    }
+                },
+                Ok(Control::WorkerDone) => running -= 1,
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Re-read the config file and apply it to all workers.
+    ///
+    /// Everything that can fail (parsing, which loads the key and certs of APIs, binding new
+    /// endpoints) is done before touching the workers, so an invalid config leaves the running one
+    /// alone. New APIs are only started once the workers took the new config.
+    fn reload(&mut self) -> Result<(), String> {
+        let path = try!(self.config.file.clone()
+            .ok_or("Configured from the command line, there is no config file to reload".to_string()));
+        info!("Reloading {}", path);
+
+        let mut config = try!(config::load(&path).map_err(|errors| config::describe(&path, &errors)));
+        try!(config.apply(self.config.overrides.clone()).map_err(|errors| config::describe(&path, &errors)));
+
+        if config.workers != self.config.workers {
+            warn!("Changing the number of workers ({} -> {}) requires a restart",
+                  self.config.workers, config.workers);
+            config.workers = self.config.workers;
+        }
+        if config.overload != self.config.overload && config.workers > 1 {
+            warn!("Endpoints keep their server sockets (one per worker with \"reject\", shared with \"backlog\") \
+                   until they are restarted or their address changes");
+        }
+
+        for endpoint in config.endpoints.iter_mut() {
+            if let Some(api) = self.apis.get(&endpoint.name) {
+                let running_config = self.config.endpoints.iter()
+                    .find(|e| e.name == endpoint.name)
+                    .and_then(|e| e.api.clone());
+                if endpoint.api != running_config {
+                    warn!("[{}] Changing API settings requires a restart", endpoint.name);
+                    endpoint.api = running_config;
+                }
+                config::route_api(endpoint, api);
+            }
+        }
+
+        let mut acceptors: Vec<Vec<Option<Acceptor>>> = self.workers.iter().map(|_| Vec::new()).collect();
+
+        for endpoint in config.endpoints.iter() {
+            let bind = match self.config.endpoints.iter().find(|e| e.name == endpoint.name) {
+                Some(running) => running.listen != endpoint.listen,
+                None => true,
+            };
+
+            let bound: Vec<Option<Acceptor>> = if bind {
+                try!(listener::bind(&endpoint.listen, config.workers, config.overload == Overload::Backlog).map_err(|e| {
+                    format!("[{}] Failed to bind server endpoint {}, {}", endpoint.name, endpoint.listen, e)
+                })).into_iter().map(Some).collect()
+            } else {
+                self.workers.iter().map(|_| None).collect()
+            };
+            for (worker_acceptors, acceptor) in acceptors.iter_mut().zip(bound.into_iter()) {
+                worker_acceptors.push(acceptor);
+            }
+        }
+
+        let (tx, rx) = channel();
+        for (worker, worker_acceptors) in self.workers.iter().zip(acceptors.into_iter()) {
+            let reload = Reload {
+                max_connections: config.worker_max_connections(),
+                configured_max_connections: config.max_connections,
+                overload: config.overload,
+                max_fails: config.max_fails,
+                fail_timeout: config.fail_timeout,
+                dns_ttl: config.dns_ttl,
+                endpoints: config.endpoints.iter().cloned().zip(worker_acceptors.into_iter()).collect(),
+            };
+            if worker.send(ApiMsg::Reload(reload, tx.clone())).is_err() {
+                return Err("Error talking to worker event_loop".to_string());
+            }
+        }
+
+        let mut errors = Vec::new();
+        for _ in self.workers.iter() {
+            match rx.recv() {
+                Ok(ApiMsg::ReloadResponse(Err(e))) => errors.push(e),
+                Ok(_) => (),
+                Err(_) => errors.push("Error talking to worker event_loop".to_string()),
+            }
+        }
+
+        if !errors.is_empty() {
+            // keep diffing against the running config, which is what the next reload has to get right
+            return Err(errors.join(", "));
+        }
+
+        let errors = self.reload_apis(&mut config);
+        self.config = config;
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join(", "))
+        }
+    }
+
+    /// Start the APIs of new endpoints (routing to them thru the workers), and shut down the ones
+    /// of removed endpoints. Returns what went wrong, if anything.
+    ///
+    /// Changing the settings of a running API requires a restart.
+    fn reload_apis(&mut self, config: &mut Config) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        for endpoint in config.endpoints.iter_mut() {
+            if self.apis.contains_key(&endpoint.name) {
+                continue;
+            }
+            match config::start_api(endpoint, self.workers.clone(), self.control.clone()) {
+                Ok(Some(api)) => {
+                    if let Err(e) = self.route_api(&endpoint.name, &api) {
+                        errors.push(format!("[{}] Failed to route to the API, {}", endpoint.name, e));
+                    }
+                    self.apis.insert(endpoint.name.clone(), api);
+                },
+                Ok(None) => (),
+                Err(e) => {
+                    errors.push(e);
+                    endpoint.api = None;
+                },
+            }
+        }
+
+        let removed: Vec<String> = self.apis.keys()
+            .filter(|name| !config.endpoints.iter().any(|e| e.name == **name))
+            .cloned()
+            .collect();
+        for name in removed {
+            if let Some(mut api) = self.apis.remove(&name) {
+                info!("[{}] Shutting down API...", name);
+                api.close();
+            }
+        }
+
+        errors
+    }
+
+    /// Add the route to a newly started API to the endpoint of every worker.
+    fn route_api(&self, name: &str, api: &RunningApi) -> Result<(), String> {
+        let mut routes = BTreeMap::new();
+        routes.insert(API_SNI.to_string(), Destinations::new(vec![format!("127.0.0.1:{}", api.local_addr.port())]));
+
+        let (tx, rx) = channel();
+        for worker in self.workers.iter() {
+            if worker.send(ApiMsg::RouteSet(name.to_string(), routes.clone(), tx.clone())).is_err() {
+                return Err("Error talking to worker event_loop".to_string());
+            }
+        }
+        for _ in self.workers.iter() {
+            match rx.recv() {
+                Ok(ApiMsg::RouteResponse(Err(e))) => return Err(e),
+                Ok(_) => (),
+                Err(_) => return Err("Error talking to worker event_loop".to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Shut down all APIs.
+    pub fn shutdown(self) {
+        for (_, mut api) in self.apis {
+            info!("Shutting down API...");
+            api.close();
+            info!("Shutting down API: ok.");
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use mio::*;
+use mio::tcp::TcpStream;
+use mio::unix::UnixStream;
+use nix::sys::socket;
+use nix::sys::socket::sockopt;
+
+/// Prefix of the paths of Unix domain sockets, in destinations and endpoints.
+pub const UNIX_PREFIX: &'static str = "unix:";
+
+/// The address of a backend (or of a listening endpoint).
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Addr {
+    Inet(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Addr {
+    /// Reads "unix:/path" (the path being absolute), or else a literal "ip:port".
+    pub fn parse(s: &str) -> Option<Addr> {
+        if s.starts_with(UNIX_PREFIX) {
+            let path = &s[UNIX_PREFIX.len()..];
+            if path.starts_with("/") && path.len() > 1 {
+                Some(Addr::Unix(PathBuf::from(path)))
+            } else {
+                None
+            }
+        } else {
+            s.parse().ok().map(Addr::Inet)
+        }
+    }
+}
+
+/// Like in the config: "ip:port" or "unix:/path".
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Addr::Inet(ref addr) => write!(f, "{}", addr),
+            Addr::Unix(ref path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// A non-blocking connection, over TCP or a Unix domain socket.
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Start connecting to the address.
+    ///
+    /// TCP connections become connected asynchronously (see `take_socket_error`), while
+    /// connecting to a Unix domain socket either succeeds or fails right away.
+    pub fn connect(addr: &Addr) -> io::Result<Stream> {
+        match *addr {
+            Addr::Inet(ref addr) => TcpStream::connect(addr).map(Stream::Tcp),
+            Addr::Unix(ref path) => UnixStream::connect(path).map(Stream::Unix),
+        }
+    }
+
+    /// The address of the peer, if it has one (clients of a Unix domain socket do not).
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match *self {
+            Stream::Tcp(ref sock) => sock.peer_addr().ok(),
+            Stream::Unix(_) => None,
+        }
+    }
+
+    /// The local address of the connection (for TCP only, like `peer_addr`).
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match *self {
+            Stream::Tcp(ref sock) => sock.local_addr().ok(),
+            Stream::Unix(_) => None,
+        }
+    }
+
+    /// Get (and clear) the pending error of the socket, i.e. whether connecting failed.
+    pub fn take_socket_error(&self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref sock) => sock.take_socket_error(),
+            Stream::Unix(ref sock) => match socket::getsockopt(sock.as_raw_fd(), sockopt::SocketError) {
+                Ok(0) => Ok(()),
+                Ok(errno) => Err(io::Error::from_raw_os_error(errno)),
+                Err(e) => Err(io::Error::new(io::ErrorKind::Other, format!("{:?}", e))),
+            },
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut sock) => sock.read(buf),
+            Stream::Unix(ref mut sock) => sock.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut sock) => sock.write(buf),
+            Stream::Unix(ref mut sock) => sock.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut sock) => sock.flush(),
+            Stream::Unix(ref mut sock) => sock.flush(),
+        }
+    }
+}
+
+impl Evented for Stream {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref sock) => sock.register(selector, token, interest, opts),
+            Stream::Unix(ref sock) => sock.register(selector, token, interest, opts),
+        }
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref sock) => sock.reregister(selector, token, interest, opts),
+            Stream::Unix(ref sock) => sock.reregister(selector, token, interest, opts),
+        }
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref sock) => sock.deregister(selector),
+            Stream::Unix(ref sock) => sock.deregister(selector),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Stream::Tcp(ref sock) => sock.as_raw_fd(),
+            Stream::Unix(ref sock) => sock.as_raw_fd(),
+        }
+    }
+}
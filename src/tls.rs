@@ -1,102 +1,566 @@
+const SSL_CONTENTTYPE_ALERT: u8 = 21;
 const SSL_CONTENTTYPE_HANDSHAKE: u8 = 22;
 const SSL_HANDSHAKE_CLIENTHELLO: u8 = 1;
-const SSL_EXTENSION_SNI: usize = 0;
+const SSL_HANDSHAKE_SERVERHELLO: u8 = 2;
 
-/// Extracts the first SNI (Server Name Indication) from a TLS client hello message.
+// size of a TLS record header: content type, version, length
+const RECORD_HEADER: usize = 5;
+
+// size of a handshake message header: type, length
+const HANDSHAKE_HEADER: usize = 4;
+
+// max length of a plaintext record (RFC 8446, 5.1)
+const MAX_RECORD_SIZE: usize = 16384;
+
+/// Default for the max size of a ClientHello (handshake message).
+pub const MAX_HELLO_SIZE: usize = 16384;
+
+pub const EXTENSION_SERVER_NAME: u16 = 0;
+pub const EXTENSION_SUPPORTED_GROUPS: u16 = 10;
+pub const EXTENSION_EC_POINT_FORMATS: u16 = 11;
+pub const EXTENSION_SIGNATURE_ALGORITHMS: u16 = 13;
+pub const EXTENSION_ALPN: u16 = 16;
+pub const EXTENSION_SUPPORTED_VERSIONS: u16 = 43;
+pub const EXTENSION_KEY_SHARE: u16 = 51;
+
+// the only type of server name there is
+const SERVER_NAME_HOST_NAME: u8 = 0;
+
+// what the ClientHello of a health check offers: the usual TLS 1.3 and 1.2 suites
+// (and TLS_EMPTY_RENEGOTIATION_INFO_SCSV), groups and signature algorithms
+const CHECK_CIPHER_SUITES: [u16; 14] = [
+    0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0x009c, 0x009d, 0x002f, 0x0035, 0x00ff,
+];
+const CHECK_GROUPS: [u16; 3] = [0x001d, 0x0017, 0x0018];
+const CHECK_SIGNATURE_ALGORITHMS: [u16; 9] = [0x0403, 0x0503, 0x0603, 0x0804, 0x0805, 0x0806, 0x0401, 0x0501, 0x0601];
+
+// an X25519 public key (the one of Alice in RFC 7748), a health check never gets to use it
+const CHECK_KEY_SHARE: [u8; 32] = [
+    0x85, 0x20, 0xf0, 0x09, 0x89, 0x30, 0xa7, 0x54, 0x74, 0x8b, 0x7d, 0xdc, 0xb4, 0x3e, 0xf7, 0x5a,
+    0x0d, 0xbf, 0x3a, 0x0d, 0x26, 0x38, 0x1a, 0xf4, 0xeb, 0xa4, 0xa9, 0x8e, 0xaa, 0x9b, 0x4e, 0x6a,
+];
+
+/// Outcome of inspecting the first bytes of an inbound connection.
+#[derive(Debug)]
+pub enum ParseResult {
+    /// Not enough bytes to decide yet, come back with at least this many.
+    NeedMore(usize),
+    /// Not a TLS ClientHello at all.
+    NotTls,
+    /// A TLS ClientHello that does not make sense.
+    Malformed(String),
+    Hello(ClientHello),
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct Extension {
+    pub kind: u16,
+    pub data: Vec<u8>,
+}
+
+/// The fields of a TLS ClientHello.
+// not every field is used by the multiplexers (yet)
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct ClientHello {
+    // version of the record layer
+    pub record_version: u16,
+    // client_version of the hello (TLS 1.3 says 1.2 here, and the truth in supported_versions)
+    pub legacy_version: u16,
+    pub random: Vec<u8>,
+    pub session_id: Vec<u8>,
+    pub cipher_suites: Vec<u16>,
+    pub compression_methods: Vec<u8>,
+    // all extensions, in the order they were sent
+    pub extensions: Vec<Extension>,
+
+    // host names of the server_name extension (SNI)
+    pub server_names: Vec<String>,
+    // protocols of the application_layer_protocol_negotiation extension (ALPN)
+    pub alpn: Vec<String>,
+    // (elliptic curve) groups the client supports
+    pub supported_groups: Vec<u16>,
+    pub ec_point_formats: Vec<u8>,
+    pub signature_algorithms: Vec<u16>,
+    pub supported_versions: Vec<u16>,
+    // groups of the key_share extension
+    pub key_share_groups: Vec<u16>,
+}
+
+impl ClientHello {
+    /// The (first) SNI (Server Name Indication).
+    pub fn sni(&self) -> Option<&str> {
+        self.server_names.first().map(|name| &name[..])
+    }
+}
+
+/// Bounds-checked reads of the big-endian fields of a TLS message.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader {
+            buf: buf,
+            pos: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+
+    fn bytes(&mut self, n: usize, what: &str) -> Result<&'a [u8], String> {
+        if self.buf.len() - self.pos < n {
+            return Err(format!("truncated {}", what));
+        }
+        let bytes = &self.buf[self.pos .. self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self, what: &str) -> Result<u8, String> {
+        self.bytes(1, what).map(|b| b[0])
+    }
+
+    fn u16(&mut self, what: &str) -> Result<u16, String> {
+        self.bytes(2, what).map(|b| (b[0] as u16) << 8 | b[1] as u16)
+    }
+
+    fn u24(&mut self, what: &str) -> Result<usize, String> {
+        self.bytes(3, what).map(|b| (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize)
+    }
+
+    /// A vector with a one byte length.
+    fn vec8(&mut self, what: &str) -> Result<Reader<'a>, String> {
+        let len = try!(self.u8(what)) as usize;
+        self.bytes(len, what).map(Reader::new)
+    }
+
+    /// A vector with a two byte length.
+    fn vec16(&mut self, what: &str) -> Result<Reader<'a>, String> {
+        let len = try!(self.u16(what)) as usize;
+        self.bytes(len, what).map(Reader::new)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        rest
+    }
+}
+
+/// Parses the TLS ClientHello at the start of an inbound connection.
 ///
-/// More stuff.
-pub fn parse_tls_client_hello(buf: &[u8]) -> Option<Option<String>> {
+/// The ClientHello may be split across several handshake records (e.g. due to large
+/// post-quantum key shares), as long as it is no bigger than `max_size`.
+///
+/// All reads are bounds-checked, so no input makes it panic (see fuzz/fuzz_targets/client_hello.rs).
+pub fn parse_client_hello(buf: &[u8], max_size: usize) -> ParseResult {
     let buf_len = buf.len();
     if buf_len < 1 {
-        return None; //want more bytes
+        return ParseResult::NeedMore(1);
     }
-    
+
     if buf[0] != SSL_CONTENTTYPE_HANDSHAKE {
         debug!("Not a handshake");
-        return Some(None); //we know it's not tls
+        return ParseResult::NotTls;
     }
-    
+
     if buf_len < 3 {
-        return None; //want more bytes
+        return ParseResult::NeedMore(RECORD_HEADER);
     }
-    
+
     if buf[1] < 3 || buf[2] < 1 {
         debug!("Bad SSL protocol version {}.{}", buf[1], buf[2]);
-        return Some(None);
+        return ParseResult::NotTls;
     }
 
-    if buf_len < 6 {
-        return None; //want more bytes
+    if buf_len < RECORD_HEADER + 1 {
+        return ParseResult::NeedMore(RECORD_HEADER + 1);
     }
-    
+
     if buf[5] != SSL_HANDSHAKE_CLIENTHELLO {
-        debug!("Not a CLIENTHELLO {}", buf[3]);
-        return Some(None); //we know it's not clienthello
+        debug!("Not a CLIENTHELLO {}", buf[5]);
+        return ParseResult::NotTls; //we know it's not clienthello
     }
-    
-    let msglen = 5 + (buf[3] as usize) * 256 + (buf[4] as usize);
-    
-    if buf_len < msglen {
-        return None; //want more bytes (entire handshake)
+
+    let record_version = (buf[1] as u16) << 8 | buf[2] as u16;
+
+    // reassemble the handshake message from the fragments in consecutive records
+    let mut msg = Vec::new();
+    let mut idx = 0;
+    loop {
+        if buf_len < idx + RECORD_HEADER {
+            return ParseResult::NeedMore(idx + RECORD_HEADER);
+        }
+
+        if buf[idx] != SSL_CONTENTTYPE_HANDSHAKE {
+            return ParseResult::Malformed(format!("ClientHello interrupted by a record of type {}", buf[idx]));
+        }
+
+        let reclen = (buf[idx + 3] as usize) * 256 + (buf[idx + 4] as usize);
+        if reclen == 0 {
+            return ParseResult::Malformed("empty handshake record".to_string());
+        }
+        if reclen > MAX_RECORD_SIZE {
+            return ParseResult::Malformed(format!("record of {} bytes exceeds the max of {}", reclen, MAX_RECORD_SIZE));
+        }
+
+        let recend = idx + RECORD_HEADER + reclen;
+        if buf_len < recend {
+            return ParseResult::NeedMore(recend); //want more bytes (entire record)
+        }
+
+        msg.extend_from_slice(&buf[idx + RECORD_HEADER .. recend]);
+        idx = recend;
+
+        if msg.len() >= HANDSHAKE_HEADER {
+            let size = HANDSHAKE_HEADER + ((msg[1] as usize) << 16 | (msg[2] as usize) << 8 | msg[3] as usize);
+            if size > max_size {
+                return ParseResult::Malformed(format!("ClientHello of {} bytes exceeds the max of {}", size, max_size));
+            }
+            if msg.len() >= size {
+                break;
+            }
+        }
     }
-    
-    let mut idx = 43usize;
-    if idx >= buf_len - 1 {
-        debug!("ClientHello is too short {}", buf_len);
-        return Some(None)
+
+    match parse_handshake(&msg, record_version) {
+        Ok(hello) => ParseResult::Hello(hello),
+        Err(reason) => ParseResult::Malformed(reason),
     }
-    
-    idx += 1 + buf[idx] as usize; //skip sessionid
+}
+
+/// Parses a ClientHello handshake message.
+fn parse_handshake(handshake: &[u8], record_version: u16) -> Result<ClientHello, String> {
+    let mut handshake = Reader::new(handshake);
+    try!(handshake.u8("handshake type"));
+    let len = try!(handshake.u24("handshake length"));
+    let mut msg = Reader::new(try!(handshake.bytes(len, "ClientHello")));
 
-    if idx >= buf_len - 2 {
-        debug!("Reached message end after sessionid.");
-        return Some(None)
+    let mut hello = ClientHello {
+        record_version: record_version,
+        legacy_version: try!(msg.u16("client version")),
+        random: try!(msg.bytes(32, "random")).to_vec(),
+        session_id: try!(msg.vec8("session id")).rest().to_vec(),
+        cipher_suites: try!(u16s(try!(msg.vec16("cipher suites")), "cipher suites")),
+        compression_methods: try!(msg.vec8("compression methods")).rest().to_vec(),
+        extensions: Vec::new(),
+        server_names: Vec::new(),
+        alpn: Vec::new(),
+        supported_groups: Vec::new(),
+        ec_point_formats: Vec::new(),
+        signature_algorithms: Vec::new(),
+        supported_versions: Vec::new(),
+        key_share_groups: Vec::new(),
+    };
+
+    if hello.session_id.len() > 32 {
+        return Err(format!("session id too long ({} bytes)", hello.session_id.len()));
     }
 
-    idx += 2 + (buf[idx] as usize) * 256 + (buf[idx + 1] as usize); //skip cipher suites
+    // no extensions at all
+    if msg.is_empty() {
+        return Ok(hello);
+    }
 
-    if idx >= buf_len - 1 {
-        debug!("Message too short while reading compression methods length");
-        return Some(None)
+    let mut extensions = try!(msg.vec16("extensions"));
+    if !msg.is_empty() {
+        return Err("trailing bytes after extensions".to_string());
     }
 
-    idx += 1 + (buf[idx] as usize); //compression methods
-    
-    if idx >= buf_len - 2 {
-        debug!("Reached message end before extension section length index");
-        return Some(None)
+    while !extensions.is_empty() {
+        let kind = try!(extensions.u16("extension type"));
+        let data = try!(extensions.vec16("extension")).rest();
+
+        match kind {
+            EXTENSION_SERVER_NAME => hello.server_names = try!(parse_server_names(data)),
+            EXTENSION_SUPPORTED_GROUPS => {
+                hello.supported_groups = try!(u16s(try!(Reader::new(data).vec16("supported groups")), "supported groups"));
+            },
+            EXTENSION_EC_POINT_FORMATS => {
+                hello.ec_point_formats = try!(Reader::new(data).vec8("ec point formats")).rest().to_vec();
+            },
+            EXTENSION_SIGNATURE_ALGORITHMS => {
+                hello.signature_algorithms = try!(u16s(try!(Reader::new(data).vec16("signature algorithms")), "signature algorithms"));
+            },
+            EXTENSION_ALPN => hello.alpn = try!(parse_alpn(data)),
+            EXTENSION_SUPPORTED_VERSIONS => {
+                hello.supported_versions = try!(u16s(try!(Reader::new(data).vec8("supported versions")), "supported versions"));
+            },
+            EXTENSION_KEY_SHARE => hello.key_share_groups = try!(parse_key_share(data)),
+            _ => (),
+        }
+
+        hello.extensions.push(Extension {
+            kind: kind,
+            data: data.to_vec(),
+        });
     }
-    
-    let extend = 2 + idx + (buf[idx] as usize) * 256 + (buf[idx + 1] as usize);
-    
-    idx += 2;
-    loop {
-        if idx < extend - 4 && idx < buf_len - 4 {
-            let extype = (buf[idx] as usize) * 256 + (buf[idx + 1] as usize);
-            let extlen = (buf[idx + 2] as usize) * 256 + (buf[idx + 3] as usize);
-            if extype == SSL_EXTENSION_SNI {
-                let sniend = 4 + idx + extlen;
-                if sniend <= buf_len {
-                    //debug!("extracting {:?} {} {}", buf, idx + 4, sniend);
-                    let sni = &buf[idx + 9 .. sniend];
-                    let vec: Vec<u8> = sni.iter().map(|c| *c).collect();
-                    //debug!("extracted sni '{:?}'", vec);
-                    return match String::from_utf8(vec) {
-                        Ok(name) => Some(Some(name)),
-                        _ => None,
-                    };
-                } else {
-                    debug!("Bad sni end {}", sniend);
-                }
+
+    Ok(hello)
+}
+
+fn u16s(mut reader: Reader, what: &str) -> Result<Vec<u16>, String> {
+    let mut result = Vec::new();
+    while !reader.is_empty() {
+        result.push(try!(reader.u16(what)));
+    }
+    Ok(result)
+}
+
+fn parse_server_names(data: &[u8]) -> Result<Vec<String>, String> {
+    let mut list = try!(Reader::new(data).vec16("server name list"));
+    let mut names = Vec::new();
+
+    while !list.is_empty() {
+        let kind = try!(list.u8("server name type"));
+        let name = try!(list.vec16("server name")).rest();
+        if kind == SERVER_NAME_HOST_NAME {
+            match String::from_utf8(name.to_vec()) {
+                Ok(name) => names.push(name),
+                Err(_) => return Err("server name is not UTF-8".to_string()),
             }
-            
-            //next extension:
-            idx += 4 + extlen;
-        } else {
-            debug!("error while parsing extension info");
-            break;
         }
     }
 
-    Some(None)
+    Ok(names)
+}
+
+/// Extracts the protocol names of an ALPN extension, skipping the ones that are not UTF-8.
+fn parse_alpn(data: &[u8]) -> Result<Vec<String>, String> {
+    let mut list = try!(Reader::new(data).vec16("ALPN protocol list"));
+    let mut protocols = Vec::new();
+
+    while !list.is_empty() {
+        let protocol = try!(list.vec8("ALPN protocol")).rest();
+        if let Ok(protocol) = String::from_utf8(protocol.to_vec()) {
+            protocols.push(protocol);
+        }
+    }
+
+    Ok(protocols)
+}
+
+fn parse_key_share(data: &[u8]) -> Result<Vec<u16>, String> {
+    let mut list = try!(Reader::new(data).vec16("key shares"));
+    let mut groups = Vec::new();
+
+    while !list.is_empty() {
+        groups.push(try!(list.u16("key share group")));
+        try!(list.vec16("key exchange"));
+    }
+
+    Ok(groups)
+}
+
+/// Builds a ClientHello (in a single record) that TLS 1.2 and 1.3 servers answer with a ServerHello,
+/// for health checks. The random is also used as the session id.
+pub fn client_hello(server_name: Option<&str>, random: &[u8; 32]) -> Vec<u8> {
+    let mut extensions = Vec::new();
+    if let Some(name) = server_name {
+        let mut list = vec![SERVER_NAME_HOST_NAME];
+        push_vec16(&mut list, name.as_bytes());
+        let mut data = Vec::new();
+        push_vec16(&mut data, &list);
+        push_extension(&mut extensions, EXTENSION_SERVER_NAME, &data);
+    }
+
+    let mut data = Vec::new();
+    push_vec16(&mut data, &u16_bytes(&CHECK_GROUPS));
+    push_extension(&mut extensions, EXTENSION_SUPPORTED_GROUPS, &data);
+
+    // uncompressed points only
+    push_extension(&mut extensions, EXTENSION_EC_POINT_FORMATS, &[1, 0]);
+
+    let mut data = Vec::new();
+    push_vec16(&mut data, &u16_bytes(&CHECK_SIGNATURE_ALGORITHMS));
+    push_extension(&mut extensions, EXTENSION_SIGNATURE_ALGORITHMS, &data);
+
+    // TLS 1.3 and 1.2
+    push_extension(&mut extensions, EXTENSION_SUPPORTED_VERSIONS, &[4, 0x03, 0x04, 0x03, 0x03]);
+
+    let mut share = u16_bytes(&[0x001d]);
+    push_vec16(&mut share, &CHECK_KEY_SHARE);
+    let mut data = Vec::new();
+    push_vec16(&mut data, &share);
+    push_extension(&mut extensions, EXTENSION_KEY_SHARE, &data);
+
+    let mut hello = vec![0x03, 0x03];
+    hello.extend_from_slice(random);
+    hello.push(random.len() as u8);
+    hello.extend_from_slice(random);
+    push_vec16(&mut hello, &u16_bytes(&CHECK_CIPHER_SUITES));
+    // no compression
+    hello.extend_from_slice(&[1, 0]);
+    push_vec16(&mut hello, &extensions);
+
+    let mut handshake = vec![SSL_HANDSHAKE_CLIENTHELLO, (hello.len() >> 16) as u8, (hello.len() >> 8) as u8, hello.len() as u8];
+    handshake.extend_from_slice(&hello);
+
+    // TLS 1.0 in the record layer, like most clients
+    let mut record = vec![SSL_CONTENTTYPE_HANDSHAKE, 0x03, 0x01];
+    push_vec16(&mut record, &handshake);
+    record
+}
+
+/// Checks the first bytes a server answers a ClientHello with, for health checks.
+///
+/// None if there are not enough bytes yet, otherwise whether they start a ServerHello.
+pub fn check_server_hello(buf: &[u8]) -> Option<Result<(), String>> {
+    if buf.len() < RECORD_HEADER + 1 {
+        return None;
+    }
+
+    match (buf[0], buf[5]) {
+        (SSL_CONTENTTYPE_HANDSHAKE, SSL_HANDSHAKE_SERVERHELLO) => Some(Ok(())),
+        (SSL_CONTENTTYPE_HANDSHAKE, kind) => Some(Err(format!("handshake message of type {} instead of a ServerHello", kind))),
+        (SSL_CONTENTTYPE_ALERT, _) if buf.len() < RECORD_HEADER + 2 => None,
+        (SSL_CONTENTTYPE_ALERT, _) => Some(Err(format!("TLS alert {}", buf[6]))),
+        (kind, _) => Some(Err(format!("record of type {} instead of a ServerHello", kind))),
+    }
+}
+
+fn u16_bytes(values: &[u16]) -> Vec<u8> {
+    values.iter().flat_map(|value| vec![(value >> 8) as u8, *value as u8]).collect()
+}
+
+/// Appends a vector with a two byte length.
+fn push_vec16(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.push((data.len() >> 8) as u8);
+    buf.push(data.len() as u8);
+    buf.extend_from_slice(data);
+}
+
+fn push_extension(buf: &mut Vec<u8>, kind: u16, data: &[u8]) {
+    buf.extend_from_slice(&u16_bytes(&[kind]));
+    push_vec16(buf, data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::path::Path;
+
+    // key share groups of the openssl samples
+    const X25519MLKEM768: u16 = 4588;
+    const X25519: u16 = 29;
+
+    fn hello(sample: &[u8]) -> ClientHello {
+        match parse_client_hello(sample, MAX_HELLO_SIZE) {
+            ParseResult::Hello(hello) => hello,
+            result => panic!("expected a hello, got {:?}", result),
+        }
+    }
+
+    fn check_openssl_hello(sample: &[u8]) {
+        let hello = hello(sample);
+        assert_eq!(hello.sni(), Some("pq.example.com"));
+        assert_eq!(hello.alpn, vec!["h2", "http/1.1"]);
+        assert_eq!(hello.key_share_groups, vec![X25519MLKEM768, X25519]);
+    }
+
+    #[test]
+    fn single_record() {
+        check_openssl_hello(include_bytes!("../testdata/client_hello/openssl-3.5-x25519mlkem768.bin"));
+    }
+
+    #[test]
+    fn two_records() {
+        check_openssl_hello(include_bytes!("../testdata/client_hello/openssl-3.5-x25519mlkem768-2-records.bin"));
+    }
+
+    #[test]
+    fn hundred_byte_records() {
+        check_openssl_hello(include_bytes!("../testdata/client_hello/openssl-3.5-x25519mlkem768-100-byte-records.bin"));
+    }
+
+    #[test]
+    fn one_byte_records() {
+        let hello = hello(include_bytes!("../testdata/client_hello/curl-7.88-no-sni-1-byte-records.bin"));
+        assert_eq!(hello.sni(), None);
+        assert_eq!(hello.alpn, vec!["h2", "http/1.1"]);
+        assert_eq!(hello.key_share_groups, vec![X25519]);
+    }
+
+    #[test]
+    fn truncated_second_record() {
+        match parse_client_hello(include_bytes!("../testdata/client_hello/truncated-2nd-record.bin"), MAX_HELLO_SIZE) {
+            ParseResult::NeedMore(1570) => (),
+            result => panic!("expected NeedMore(1570), got {:?}", result),
+        }
+    }
+
+    #[derive(Debug)]
+    enum Expected {
+        Hello(Option<&'static str>),
+        NeedMore(usize),
+        Malformed,
+        NotTls,
+    }
+
+    // what testdata/client_hello/README.md says about each sample
+    const SAMPLES: [(&'static str, Expected); 23] = [
+        ("openssl-3.5-x25519mlkem768.bin", Expected::Hello(Some("pq.example.com"))),
+        ("openssl-3.5-x25519mlkem768-2-records.bin", Expected::Hello(Some("pq.example.com"))),
+        ("openssl-3.5-x25519mlkem768-100-byte-records.bin", Expected::Hello(Some("pq.example.com"))),
+        ("curl-7.88-no-sni.bin", Expected::Hello(None)),
+        ("curl-7.88-no-sni-1-byte-records.bin", Expected::Hello(None)),
+        ("truncated-2nd-record.bin", Expected::NeedMore(1570)),
+        ("interrupted-by-alert.bin", Expected::Malformed),
+        ("empty-record.bin", Expected::Malformed),
+        ("oversized.bin", Expected::Malformed),
+        ("record-too-long.bin", Expected::Malformed),
+        ("no-extensions.bin", Expected::Hello(None)),
+        ("empty-hello.bin", Expected::Malformed),
+        ("handshake-length-short.bin", Expected::Malformed),
+        ("session-id-too-long.bin", Expected::Malformed),
+        ("extensions-overflow.bin", Expected::Malformed),
+        ("extension-overflow.bin", Expected::Malformed),
+        ("sni-list-overflow.bin", Expected::Malformed),
+        ("sni-name-overflow.bin", Expected::Malformed),
+        ("sni-empty-list.bin", Expected::Hello(None)),
+        ("sni-not-utf8.bin", Expected::Malformed),
+        ("alpn-overflow.bin", Expected::Malformed),
+        ("http-request.bin", Expected::NotTls),
+        ("sslv2-hello.bin", Expected::NotTls),
+    ];
+
+    #[test]
+    fn corpus() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/client_hello");
+        let mut checked = 0;
+
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(true, |extension| extension != "bin") {
+                continue;
+            }
+            let name = path.file_name().unwrap().to_str().unwrap().to_string();
+            let expected = match SAMPLES.iter().find(|&&(sample, _)| sample == name) {
+                Some(&(_, ref expected)) => expected,
+                None => panic!("{} is missing from SAMPLES (and the README)", name),
+            };
+
+            let mut sample = Vec::new();
+            File::open(&path).unwrap().read_to_end(&mut sample).unwrap();
+            let result = parse_client_hello(&sample, MAX_HELLO_SIZE);
+            match (expected, &result) {
+                (&Expected::Hello(sni), &ParseResult::Hello(ref hello)) if hello.sni() == sni => (),
+                (&Expected::NeedMore(n), &ParseResult::NeedMore(m)) if n == m => (),
+                (&Expected::Malformed, &ParseResult::Malformed(_)) => (),
+                (&Expected::NotTls, &ParseResult::NotTls) => (),
+                _ => panic!("{}: expected {:?}, got {:?}", name, expected, result),
+            }
+            checked += 1;
+        }
+
+        assert_eq!(checked, 23);
+    }
 }